@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use futures::{stream, StreamExt};
+use tokio_postgres::Client;
+use tokio_postgres::types::Type;
+use crate::chunks;
+use crate::cli;
+use crate::compare;
+use crate::connection;
+use crate::exit::ErrorKind;
+use crate::pg_hasher;
+use crate::query;
+
+/// A range of key values, from lo up to (but not including) hi.
+/// The first range also holds the rows without a key value.
+#[derive(Clone, Copy)]
+struct KeyRange {
+    lo: i128,
+    hi: i128,
+    nulls: bool,
+}
+
+impl KeyRange {
+    fn condition(&self, column: &str) -> String {
+        let name = pg_hasher::str_as_name(column);
+        if self.nulls {
+            format!("({name} >= {} and {name} < {} or {name} is null)", self.lo, self.hi, name = name)
+        } else {
+            format!("{name} >= {} and {name} < {}", self.lo, self.hi, name = name)
+        }
+    }
+
+    fn bisect(&self) -> (KeyRange, KeyRange) {
+        let mid = self.lo + (self.hi - self.lo) / 2;
+        (KeyRange { lo: self.lo, hi: mid, nulls: self.nulls }, KeyRange { lo: mid, hi: self.hi, nulls: false })
+    }
+}
+
+/// The query of one side that the checksums of the ranges are computed over, built once per table:
+/// the rows with a key value from $1 up to and including $2, and those without one when $3 is true.
+/// The columns are checked like for a comparison, so that the unsupported types policy applies.
+/// Returns the query, the names and types of its columns, and warnings for the report.
+async fn checksum_query(client: &Client, table_name: &str, side: &str, column: &str, args: &cli::Params,
+                        hash_options: &pg_hasher::HashOptions, unsupported_types: query::UnsupportedTypes)
+                        -> Result<(String, Vec<(String, Type)>, Vec<String>)> {
+    let name = pg_hasher::str_as_name(column);
+    let condition = format!("({name} >= $1::int8 and {name} <= $2::int8 or $3::bool and {name} is null)", name = name);
+    let range_args = chunks::chunk_args(args, &condition);
+    let query = query::build_table_query(client, table_name, &range_args).await.context(ErrorKind::Query)?;
+    let statement = client.prepare(&query).await.context(ErrorKind::Query)?;
+    let (checked, warnings) = query::check_column_types(
+        &query, side, statement.columns(), &args.key_columns, unsupported_types).context(ErrorKind::Type)?;
+    pg_hasher::normalize::check(&hash_options.normalize, side, statement.columns())?;
+    let types = statement.columns().iter().map(|c| (String::from(c.name()), c.type_().clone())).collect();
+    Ok((checked.unwrap_or(query), types, warnings))
+}
+
+/// The number of rows and the sum of the (64 bit) row hashes of a range, computed on the server.
+/// The sum does not depend on the order of the rows. The hashes are of the text of the rows, so
+/// values that are only equal after normalization make the range differ, and its rows are compared.
+async fn range_checksum(client: &Client, checksum_query: &str, range: KeyRange) -> Result<(i64, String)> {
+    let row = client.query_one(
+        format!("select count(*)::int8, \
+                 coalesce(sum(('x' || substr(md5(dbdiff_query::text), 1, 16))::bit(64)::int8), 0)::text \
+                 from ({}) as dbdiff_query", checksum_query).as_str(),
+        &[&(range.lo as i64), &((range.hi - 1) as i64), &range.nulls],
+    ).await?;
+    Ok((row.get(0), row.get(1)))
+}
+
+/// Compare the checksums of a range on both sides. Returns the number of rows of both sides,
+/// and whether the checksums are equal.
+async fn compare_range(clients: &(Client, Client), queries: &(String, String), range: KeyRange) -> Result<(i64, i64, bool)> {
+    let (source, dest) = clients;
+    let (source_rows, source_sum) = range_checksum(source, &queries.0, range).await?;
+    let (dest_rows, dest_sum) = range_checksum(dest, &queries.1, range).await?;
+    Ok((source_rows, dest_rows, source_rows == dest_rows && source_sum == dest_sum))
+}
+
+/// Compare a table by first comparing checksums of key ranges on the servers.
+/// Ranges with different checksums are bisected until they hold at most args.checksum_rows rows,
+/// and only the rows of those ranges are read and compared.
+pub async fn compare_checksummed(source: &Client, dest: &Client, args: &cli::Params, hash_options: &pg_hasher::HashOptions,
+                                 unsupported_types: query::UnsupportedTypes) -> Result<(Vec<String>, compare::Comparison)> {
+    if !args.source_query.is_empty() || !args.dest_query.is_empty() {
+        return Err(anyhow::anyhow!("Checksums require a table (--source-table-name) instead of a query"));
+    }
+    let column = chunks::chunk_column(source, args).await.context(ErrorKind::Query)?
+        .ok_or_else(|| anyhow::anyhow!("Checksums require a single integer key column or primary key"))?;
+    // The checksum queries don't need to be ordered
+    let mut checksum_args = args.clone();
+    checksum_args.sorted = false;
+
+    let ranges = [
+        chunks::column_range(source, &args.source_table_name, &column, args).await.context(ErrorKind::Query)?,
+        chunks::column_range(dest, &args.dest_table_name, &column, args).await.context(ErrorKind::Query)?,
+    ];
+    let from = ranges.iter().flatten().map(|(min, _max)| *min).min();
+    let to = ranges.iter().flatten().map(|(_min, max)| *max).max();
+    let (source_query, source_types, mut warnings) = checksum_query(
+        source, &args.source_table_name, "source", &column, &checksum_args, hash_options, unsupported_types).await?;
+    let (dest_query, dest_types, dest_warnings) = checksum_query(
+        dest, &args.dest_table_name, "destination", &column, &checksum_args, hash_options, unsupported_types).await?;
+    warnings.extend(dest_warnings);
+    let queries = (source_query, dest_query);
+
+    let mut pending: Vec<KeyRange> = Vec::new();
+    if let Some((from, to)) = from.zip(to) {
+        let mut lo = from as i128;
+        for bound in chunks::boundaries(from, to, args.chunks) {
+            pending.push(KeyRange { lo, hi: bound as i128, nulls: pending.is_empty() });
+            lo = bound as i128;
+        }
+        pending.push(KeyRange { lo, hi: to as i128 + 1, nulls: pending.is_empty() });
+    } else {
+        // No key values on either side, but there can still be rows without one: an empty range with the nulls
+        pending.push(KeyRange { lo: 0, hi: 0, nulls: true });
+    }
+
+    let mut pool: Vec<(Client, Client)> = Vec::new();
+    for _ in 0..args.parallel.max(1) {
+        pool.push(connection::connect_pair(args).await?);
+    }
+    let mut identical_source_rows: u64 = 0;
+    let mut identical_dest_rows: u64 = 0;
+    let mut mismatches: Vec<KeyRange> = Vec::new();
+    // The text of the rows doesn't show the types, and with strict types no row can be equal
+    if hash_options.strict_types && source_types != dest_types {
+        mismatches = std::mem::take(&mut pending);
+    }
+    while !pending.is_empty() {
+        let checksums: Vec<Result<(i64, i64, bool)>> = stream::iter(pending.clone().into_iter().enumerate())
+            .map(|(i, range)| compare_range(&pool[i % pool.len()], &queries, range))
+            .buffered(pool.len())
+            .collect()
+            .await;
+        let mut next: Vec<KeyRange> = Vec::new();
+        for (range, checksum) in pending.iter().zip(checksums) {
+            let (source_rows, dest_rows, equal) = checksum.context(ErrorKind::Query)?;
+            if equal {
+                identical_source_rows += source_rows as u64;
+                identical_dest_rows += dest_rows as u64;
+            } else if source_rows.max(dest_rows) as usize <= args.checksum_rows || range.hi - range.lo <= 1 {
+                mismatches.push(*range);
+            } else {
+                let (first, second) = range.bisect();
+                next.push(first);
+                next.push(second);
+            }
+        }
+        pending = next;
+    }
+
+    // Compare the rows of the ranges that differ, in key order
+    mismatches.sort_by_key(|range| range.lo);
+    let jobs: Vec<cli::Params> = mismatches.iter()
+        .map(|range| chunks::chunk_args(args, &range.condition(&column)))
+        .collect();
+    let (chunk_warnings, mut comparison) = chunks::compare_chunks(args, jobs, hash_options, unsupported_types).await?;
+    for warning in chunk_warnings {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+    comparison.source_rows += identical_source_rows;
+    comparison.dest_rows += identical_dest_rows;
+    Ok((warnings, comparison))
+}
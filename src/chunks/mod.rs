@@ -0,0 +1,247 @@
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use futures::{pin_mut, stream, StreamExt};
+use tokio_postgres::Client;
+use crate::cli;
+use crate::compare;
+use crate::connection;
+use crate::differ;
+use crate::exit::ErrorKind;
+use crate::pg_hasher;
+use crate::query;
+use crate::tables;
+
+/// What a chunk comparison leaves to be merged with the other chunks
+enum ChunkOutcome {
+    /// Rows that did not match within the chunk (ctid chunks can match rows in other chunks)
+    Unmatched(differ::UnmatchedRows, differ::UnmatchedRows),
+    /// Differences of a merge join, which only works for key chunks
+    Diffs(Vec<differ::RowDiff>),
+}
+
+struct ChunkResult {
+    warnings: Vec<String>,
+    source_rows: u64,
+    dest_rows: u64,
+    truncated: bool,
+    outcome: ChunkOutcome,
+}
+
+/// The (single) integer column to split a table by: the key column, or else the primary key
+pub async fn chunk_column(client: &Client, args: &cli::Params) -> Result<Option<String>> {
+    let key_columns = if args.key_columns.is_empty() {
+        tables::primary_key(client, &args.source_table_name).await?
+    } else {
+        args.key_columns.clone()
+    };
+    if key_columns.len() != 1 {
+        return Ok(None);
+    }
+    let rows = client.query(
+        "select t.typname::text from pg_catalog.pg_attribute a \
+         join pg_catalog.pg_type t on t.oid = a.atttypid \
+         where a.attrelid = to_regclass($1) and a.attname = $2 and t.typname in ('int2', 'int4', 'int8')",
+        &[&pg_hasher::table_as_name(&args.source_table_name), &key_columns[0]],
+    ).await?;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(key_columns[0].clone()))
+}
+
+/// The lowest and highest value of a column, within the where clause
+pub async fn column_range(client: &Client, table_name: &str, column: &str, args: &cli::Params) -> Result<Option<(i64, i64)>> {
+    let mut query = format!("select min({col})::int8, max({col})::int8 from {}",
+                            pg_hasher::table_as_name(table_name), col = pg_hasher::str_as_name(column));
+    if !args.where_clause.is_empty() {
+        query = format!("{} where ({})", query, args.where_clause);
+    }
+    let row = client.query_one(query.as_str(), &[]).await?;
+    let min: Option<i64> = row.get(0);
+    let max: Option<i64> = row.get(1);
+    Ok(min.zip(max))
+}
+
+/// The number of pages of a table
+async fn table_pages(client: &Client, table_name: &str) -> Result<i64> {
+    let row = client.query_one(
+        "select pg_catalog.pg_relation_size(to_regclass($1)) / pg_catalog.current_setting('block_size')::int8",
+        &[&pg_hasher::table_as_name(table_name)],
+    ).await?;
+    Ok(row.get(0))
+}
+
+/// Conditions that split the values of expr in ranges at the boundaries.
+/// The first and the last range are open, so that rows outside of the planned ranges are compared too.
+fn range_conditions(expr: &str, boundaries: &[String], first_extra: &str) -> Vec<String> {
+    let mut conditions: Vec<String> = Vec::new();
+    for (i, boundary) in boundaries.iter().enumerate() {
+        if i == 0 {
+            conditions.push(format!("({} < {}{})", expr, boundary, first_extra));
+        } else {
+            conditions.push(format!("{} >= {} and {} < {}", expr, boundaries[i - 1], expr, boundary));
+        }
+    }
+    if let Some(last) = boundaries.last() {
+        conditions.push(format!("{} >= {}", expr, last));
+    }
+    conditions
+}
+
+/// The boundaries that split from..=to in (at most) chunks ranges
+pub fn boundaries(from: i64, to: i64, chunks: usize) -> Vec<i64> {
+    let size = ((to as i128 - from as i128 + 1) + chunks as i128 - 1) / chunks as i128;
+    (1..chunks as i128)
+        .map(|i| from as i128 + i * size.max(1))
+        .filter(|b| *b <= to as i128)
+        .map(|b| b as i64)
+        .collect()
+}
+
+/// Split the table in chunks, and return the where clause of every chunk
+async fn plan_chunks(source: &Client, dest: &Client, args: &cli::Params) -> Result<Vec<String>> {
+    let column = match args.chunk_by.as_str() {
+        "key" | "auto" => chunk_column(source, args).await?,
+        "ctid" => None,
+        _ => return Err(anyhow::anyhow!("Invalid chunk method {}, use key, ctid or auto", args.chunk_by)),
+    };
+    let conditions = match column {
+        Some(col) => {
+            let ranges = [
+                column_range(source, &args.source_table_name, &col, args).await?,
+                column_range(dest, &args.dest_table_name, &col, args).await?,
+            ];
+            let from = ranges.iter().flatten().map(|(min, _max)| *min).min();
+            let to = ranges.iter().flatten().map(|(_min, max)| *max).max();
+            let bounds: Vec<String> = match from.zip(to) {
+                Some((from, to)) => boundaries(from, to, args.chunks).iter().map(|b| b.to_string()).collect(),
+                None => Vec::new(),
+            };
+            let name = pg_hasher::str_as_name(&col);
+            range_conditions(&name, &bounds, &format!(" or {} is null", name))
+        },
+        None if args.chunk_by == "key" =>
+            return Err(anyhow::anyhow!("Chunks by key require a single integer key column or primary key")),
+        None => {
+            if args.sorted {
+                return Err(anyhow::anyhow!("Sorted (merge join) comparison of chunks requires chunks by key"));
+            }
+            let pages = table_pages(source, &args.source_table_name).await?
+                .max(table_pages(dest, &args.dest_table_name).await?);
+            let bounds: Vec<String> = boundaries(0, pages - 1, args.chunks).iter()
+                .map(|b| format!("'({},0)'::tid", b))
+                .collect();
+            range_conditions("ctid", &bounds, "")
+        },
+    };
+    if conditions.is_empty() {
+        // Nothing to split: compare the table as a whole
+        return Ok(vec![String::new()]);
+    }
+    Ok(conditions)
+}
+
+/// Compare a chunk, counting its unmatched rows in those of all chunks of the table
+async fn compare_chunk(args: cli::Params, hash_options: pg_hasher::HashOptions, unsupported_types: query::UnsupportedTypes,
+                       unmatched_rows: Arc<compare::SharedUnmatched>) -> Result<ChunkResult> {
+    let (source, dest) = connection::connect_pair(&args).await?;
+    let (source_rows, dest_rows, warnings) = compare::query_streams(&source, &dest, &args, &hash_options, unsupported_types).await?;
+    pin_mut!(source_rows);
+    pin_mut!(dest_rows);
+    if args.sorted {
+        let mut diffs: Vec<differ::RowDiff> = Vec::new();
+        let (source_read, dest_read) = differ::merge_diff(
+            &args.key_columns, &hash_options, source_rows.as_mut(), dest_rows.as_mut(),
+            |diff| {
+                diffs.push(diff);
+                Ok(())
+            },
+        ).await?;
+        return Ok(ChunkResult {
+            warnings, source_rows: source_read, dest_rows: dest_read, truncated: false, outcome: ChunkOutcome::Diffs(diffs),
+        });
+    }
+    let unmatched = compare::hash_unmatched(
+        source_rows.as_mut(), dest_rows.as_mut(), &args, &hash_options, Some(&unmatched_rows)).await?;
+    let (source_read, dest_read, truncated) = (unmatched.source_rows, unmatched.dest_rows, unmatched.truncated);
+    let (source_unmatched, dest_unmatched) = unmatched.into_rows()?;
+    Ok(ChunkResult {
+        warnings,
+        source_rows: source_read,
+        dest_rows: dest_read,
+        truncated,
+        outcome: ChunkOutcome::Unmatched(source_unmatched, dest_unmatched),
+    })
+}
+
+/// Compare a table in args.chunks chunks, with at most args.parallel chunks at the same time,
+/// each on its own pair of connections. The source and dest clients are used to plan the chunks.
+pub async fn compare_chunked(source: &Client, dest: &Client, args: &cli::Params, hash_options: &pg_hasher::HashOptions,
+                             unsupported_types: query::UnsupportedTypes) -> Result<(Vec<String>, compare::Comparison)> {
+    if !args.source_query.is_empty() || !args.dest_query.is_empty() {
+        return Err(anyhow::anyhow!("Chunks require a table (--source-table-name) instead of a query"));
+    }
+    let conditions = plan_chunks(source, dest, args).await.context(ErrorKind::Query)?;
+    let jobs: Vec<cli::Params> = conditions.iter().map(|condition| chunk_args(args, condition)).collect();
+    compare_chunks(args, jobs, hash_options, unsupported_types).await
+}
+
+/// The settings for a chunk: the where clause is extended with the condition of the chunk
+pub fn chunk_args(args: &cli::Params, condition: &str) -> cli::Params {
+    let mut chunk_args = args.clone();
+    if !condition.is_empty() {
+        chunk_args.where_clause = if args.where_clause.is_empty() {
+            String::from(condition)
+        } else {
+            format!("({}) and {}", args.where_clause, condition)
+        };
+    }
+    chunk_args
+}
+
+/// Compare the chunks (in key order for a merge join), with at most args.parallel chunks at the same time,
+/// and merge the differences
+pub async fn compare_chunks(args: &cli::Params, jobs: Vec<cli::Params>, hash_options: &pg_hasher::HashOptions,
+                            unsupported_types: query::UnsupportedTypes) -> Result<(Vec<String>, compare::Comparison)> {
+    // Tasks are only spawned when buffered polls for them, and results come in chunk (and key) order.
+    // args.max_unmatched applies to the unmatched rows that the running chunks hold together.
+    let unmatched_rows = Arc::new(compare::SharedUnmatched::default());
+    let results: Vec<Result<Result<ChunkResult>, tokio::task::JoinError>> = stream::iter(jobs)
+        .map(|chunk_args| tokio::spawn(compare_chunk(chunk_args, hash_options.clone(), unsupported_types, unmatched_rows.clone())))
+        .buffered(args.parallel.max(1))
+        .collect()
+        .await;
+
+    let mut warnings: Vec<String> = Vec::new();
+    let mut source_rows: u64 = 0;
+    let mut dest_rows: u64 = 0;
+    let mut truncated: bool = false;
+    let mut diffs: Vec<differ::RowDiff> = Vec::new();
+    let mut source_unmatched = differ::UnmatchedRows::new();
+    let mut dest_unmatched = differ::UnmatchedRows::new();
+    for result in results {
+        let chunk = result??;
+        // Every chunk runs the same query, so it has the same warnings
+        for warning in chunk.warnings {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+        source_rows += chunk.source_rows;
+        dest_rows += chunk.dest_rows;
+        truncated |= chunk.truncated;
+        match chunk.outcome {
+            ChunkOutcome::Diffs(chunk_diffs) => diffs.extend(chunk_diffs),
+            ChunkOutcome::Unmatched(source_rows, dest_rows) => {
+                source_unmatched.merge(source_rows, &mut dest_unmatched);
+                dest_unmatched.merge(dest_rows, &mut source_unmatched);
+            },
+        }
+    }
+    let differences = if args.sorted {
+        compare::Differences::Keyed(diffs)
+    } else {
+        compare::differences(args, hash_options, source_unmatched, dest_unmatched)?
+    };
+    Ok((warnings, compare::Comparison { source_rows, dest_rows, truncated, differences }))
+}
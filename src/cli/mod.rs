@@ -1,8 +1,25 @@
 use std::env;
+use std::path::PathBuf;
+use anyhow::Result;
+use structopt::clap::ArgMatches;
 use structopt::StructOpt;
+use crate::exit;
+use crate::job;
+use crate::pg_hasher;
+
+#[derive(StructOpt, Clone)]
+pub enum Command {
+    /// Compare the schema (tables, columns, constraints, indexes, triggers, sequences and views)
+    /// in --schema (default public) instead of the data
+    Schema {
+        /// Print the statements that make the destination schema equal to the source schema
+        #[structopt(long = "alter")]
+        alter: bool,
+    },
+}
 
 /// Search for a pattern in a file and display the lines that contain it.
-#[derive(StructOpt)]
+#[derive(StructOpt, Clone)]
 pub struct Params {
     /// Source connection string
     #[structopt(long = "source_dsn")]
@@ -14,12 +31,12 @@ pub struct Params {
     #[structopt(default_value, long)]
     pub dest_dsn: String,
 
-    /// Table name for insert queries into the source
+    /// Source table (optionally schema qualified), to build the source query from and for insert queries
     #[structopt(short = "t", long = "sourcetable")]
     #[structopt(default_value, long)]
     pub source_table_name: String,
 
-    /// Table name for insert queries into the dest
+    /// Destination table (optionally schema qualified), defaults to the source table
     #[structopt(long = "desttable")]
     #[structopt(default_value, long)]
     pub dest_table_name: String,
@@ -39,24 +56,240 @@ pub struct Params {
     #[structopt(default_value, long)]
     pub dest_query: String,
 
-    /// Max number of rows to not match
+    /// Max number of rows to not match, for the chunks of a table that run at the same time together
     #[structopt(long = "max_unmatched")]
     #[structopt(default_value, long)]
     pub max_unmatched: usize,
+
+    /// Instead of stopping at --max-unmatched unmatched rows, move them to files in this directory and go on.
+    /// Differences are printed as the spilled rows are paired at the end (except for the json format)
+    #[structopt(long = "spill-dir", default_value)]
+    pub spill_dir: String,
+
+    /// Move unmatched rows to disk when they take more than this many MB of memory (0 for no limit).
+    /// Uses --spill-dir, or else the temporary directory
+    #[structopt(long = "max-memory", default_value)]
+    pub max_memory: usize,
+
+    /// Key column(s) to pair source and destination rows by (comma separated or repeated)
+    #[structopt(short = "k", long = "key", use_delimiter = true)]
+    pub key_columns: Vec<String>,
+
+    /// For the sync format, generate a script that makes the source equal to the destination instead
+    #[structopt(long = "reverse")]
+    pub reverse: bool,
+
+    /// Turn off --reverse when the job file or DBDIFF_REVERSE turns it on
+    #[structopt(long = "no-reverse", overrides_with = "reverse")]
+    pub no_reverse: bool,
+
+    /// Both queries are ordered by the key columns: compare with a streaming merge join in constant memory.
+    /// The updates and inserts of a sync script are kept in temporary files in the spill directory, to print them after the deletes.
+    /// Key columns cannot be normalized with trim, lowercase or sort-keys, which can change their order
+    #[structopt(long = "sorted")]
+    pub sorted: bool,
+
+    /// Turn off --sorted when the job file or DBDIFF_SORTED turns it on
+    #[structopt(long = "no-sorted", overrides_with = "sorted")]
+    pub no_sorted: bool,
+
+    /// Algorithm to fingerprint rows with (xxh3 or sha256)
+    #[structopt(long = "fingerprint")]
+    #[structopt(default_value, long)]
+    pub fingerprint: String,
+
+    /// Ignore trailing zeros of numeric values when comparing (1.50 equals 1.5)
+    #[structopt(long = "trim-numeric-scale")]
+    pub trim_numeric_scale: bool,
+
+    /// Turn off --trim-numeric-scale when the job file or DBDIFF_TRIM_NUMERIC_SCALE turns it on
+    #[structopt(long = "no-trim-numeric-scale", overrides_with = "trim-numeric-scale")]
+    pub no_trim_numeric_scale: bool,
+
+    /// Normalize column values before comparing, as column:rule (comma separated or repeated).
+    /// Rules are trim, lowercase, round=<ms|s|min|h|d>, epsilon=<e> (round floats to the nearest multiple of e),
+    /// digits=<n> and sort-keys. Use * as the column to normalize all columns the rule applies to
+    #[structopt(long = "normalize", use_delimiter = true)]
+    pub normalize: Vec<String>,
+
+    /// Values of different column types never compare equal. By default, logically equal values of
+    /// compatible types do (int4 and int8, char(n) and varchar, timestamp and timestamptz, json and jsonb).
+    /// Rows still pair up by the values of their keys, and a column of another type is reported as changed
+    #[structopt(long = "strict-types")]
+    pub strict_types: bool,
+
+    /// Turn off --strict-types when the job file or DBDIFF_STRICT_TYPES turns it on
+    #[structopt(long = "no-strict-types", overrides_with = "strict-types")]
+    pub no_strict_types: bool,
+
+    /// What to do with columns of unsupported types: error, cast (to text on the server) or exclude
+    #[structopt(long = "unsupported-types")]
+    #[structopt(default_value, long)]
+    pub unsupported_types: String,
+
+    /// What to do with a column value that cannot be decoded: abort (the comparison with an error),
+    /// or record (warn, and compare the value as undecodable)
+    #[structopt(long = "decode-errors")]
+    #[structopt(default_value, long)]
+    pub decode_errors: String,
+
+    /// Only compare these columns of the table(s) (comma separated or repeated)
+    #[structopt(long = "columns", use_delimiter = true)]
+    pub columns: Vec<String>,
+
+    /// Leave these columns of the table(s) out of the comparison (comma separated or repeated)
+    #[structopt(long = "exclude-columns", use_delimiter = true)]
+    pub exclude_columns: Vec<String>,
+
+    /// First compare checksums of key ranges on the servers, and only read the rows of ranges that differ
+    #[structopt(long = "checksum")]
+    pub checksum: bool,
+
+    /// Turn off --checksum when the job file or DBDIFF_CHECKSUM turns it on
+    #[structopt(long = "no-checksum", overrides_with = "checksum")]
+    pub no_checksum: bool,
+
+    /// Ranges with different checksums are split until they hold at most this many rows
+    #[structopt(long = "checksum-rows", default_value)]
+    pub checksum_rows: usize,
+
+    /// Filter for the rows of the table(s), like "created_at > now() - interval '1 day'"
+    #[structopt(long = "where", default_value)]
+    pub where_clause: String,
+
+    /// Compare all tables in this schema (or those matching --tables)
+    #[structopt(long = "schema", default_value)]
+    pub schema: String,
+
+    /// Schema of the tables on the destination, defaults to --schema
+    #[structopt(long = "dest-schema", default_value)]
+    pub dest_schema: String,
+
+    /// Compare these tables (comma separated or repeated, * and ? are wildcards), in --schema or public
+    #[structopt(long = "tables", use_delimiter = true)]
+    pub tables: Vec<String>,
+
+    /// Max number of tables (or chunks of a table) to compare at the same time
+    #[structopt(long = "parallel", default_value)]
+    pub parallel: usize,
+
+    /// Split the table(s) in this many chunks, that are compared on their own connections
+    #[structopt(long = "chunks", default_value)]
+    pub chunks: usize,
+
+    /// How to split tables in chunks: key (ranges of a single integer key column),
+    /// ctid (ranges of pages) or auto (key when possible)
+    #[structopt(long = "chunk-by", default_value)]
+    pub chunk_by: String,
+
+    /// TLS mode for the source connection (disable, allow, prefer, require, verify-ca or verify-full),
+    /// overrides sslmode in the source connection string
+    #[structopt(long = "source-sslmode", default_value)]
+    pub source_sslmode: String,
+
+    /// Root certificate(s) to verify the source server with
+    #[structopt(long = "source-sslrootcert", default_value)]
+    pub source_sslrootcert: String,
+
+    /// Client certificate for the source connection
+    #[structopt(long = "source-sslcert", default_value)]
+    pub source_sslcert: String,
+
+    /// Private key of the client certificate for the source connection
+    #[structopt(long = "source-sslkey", default_value)]
+    pub source_sslkey: String,
+
+    /// TLS mode for the destination connection, overrides sslmode in the destination connection string
+    #[structopt(long = "dest-sslmode", default_value)]
+    pub dest_sslmode: String,
+
+    /// Root certificate(s) to verify the destination server with
+    #[structopt(long = "dest-sslrootcert", default_value)]
+    pub dest_sslrootcert: String,
+
+    /// Client certificate for the destination connection
+    #[structopt(long = "dest-sslcert", default_value)]
+    pub dest_sslcert: String,
+
+    /// Private key of the client certificate for the destination connection
+    #[structopt(long = "dest-sslkey", default_value)]
+    pub dest_sslkey: String,
+
+    /// File with the password for the source connection on its first line
+    #[structopt(long = "source-password-file", default_value)]
+    pub source_password_file: String,
+
+    /// Shell command that prints the password for the source connection
+    #[structopt(long = "source-password-command", default_value)]
+    pub source_password_command: String,
+
+    /// The password printed by --source-password-command, see connection::resolve_passwords
+    #[structopt(skip)]
+    pub source_password: String,
+
+    /// File with the password for the destination connection on its first line
+    #[structopt(long = "dest-password-file", default_value)]
+    pub dest_password_file: String,
+
+    /// Shell command that prints the password for the destination connection
+    #[structopt(long = "dest-password-command", default_value)]
+    pub dest_password_command: String,
+
+    /// The password printed by --dest-password-command
+    #[structopt(skip)]
+    pub dest_password: String,
+
+    /// Read each side in a repeatable read, read only transaction, so that all queries (and connections)
+    /// of a side see the same data
+    #[structopt(long = "snapshot")]
+    pub snapshot: bool,
+
+    /// Turn off --snapshot when the job file or DBDIFF_SNAPSHOT turns it on
+    #[structopt(long = "no-snapshot", overrides_with = "snapshot")]
+    pub no_snapshot: bool,
+
+    /// Read the source in this exported snapshot (from pg_export_snapshot(), or a replication slot
+    /// created with EXPORT_SNAPSHOT), implies --snapshot
+    #[structopt(long = "source-snapshot", default_value)]
+    pub source_snapshot: String,
+
+    /// Read the destination in this exported snapshot, implies --snapshot
+    #[structopt(long = "dest-snapshot", default_value)]
+    pub dest_snapshot: String,
+
+    /// The WAL position of the source when dbdiff exported its snapshot, see connection::share_snapshots
+    #[structopt(skip)]
+    pub source_snapshot_lsn: String,
+
+    /// The WAL position of the destination when dbdiff exported its snapshot
+    #[structopt(skip)]
+    pub dest_snapshot_lsn: String,
+
+    /// Job file with the connections, options and table or query pairs to compare (YAML, or TOML
+    /// for a .toml file). Options on the command line and DBDIFF_* variables take precedence
+    #[structopt(long = "job", default_value)]
+    pub job: String,
+
+    /// Print the resolved settings as a job file, with passwords masked, and exit
+    #[structopt(long = "print-config")]
+    pub print_config: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
 fn get_str_default(val: &str, env_key: &str, default: &str) -> String {
     if !val.is_empty() {
-        return format!("{}", val);
+        return val.to_string();
     }
     match env::var(env_key) {
         Ok(env_val) => env_val,
-        Err(_e) => format!("{}", default),
+        Err(_e) => default.to_string(),
     }
 }
 
-fn get_int_default(val: u32, env_key: &str, default: u32) -> u32 {
-    if val > 0 {
+fn get_int_default(val: Option<u32>, env_key: &str, default: u32) -> u32 {
+    if let Some(val) = val {
         return val;
     }
     if let Ok(env_val) = env::var(env_key) {
@@ -67,41 +300,223 @@ fn get_int_default(val: u32, env_key: &str, default: u32) -> u32 {
     default
 }
 
-// fn get_bool_default(val: bool, env_key: String) -> bool {
-//     if val {
-//         return val;
-//     }
-//     if let Ok(mut env_val) = env::var(env_key) {
-//         env_val.make_ascii_lowercase();
-//         if let Ok(env_bool_val) = env_val.parse::<bool>() {
-//             return env_bool_val;
-//         }
-//     }
-//     false
-// }
+fn get_list_default(val: Vec<String>, env_key: &str, default: &[String]) -> Vec<String> {
+    if !val.is_empty() {
+        return val;
+    }
+    match env::var(env_key) {
+        Ok(env_val) => env_val.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        Err(_e) => default.to_vec(),
+    }
+}
+
+fn get_bool_default(val: Option<bool>, env_key: &str, default: bool) -> bool {
+    if let Some(val) = val {
+        return val;
+    }
+    if let Ok(mut env_val) = env::var(env_key) {
+        env_val.make_ascii_lowercase();
+        if let Ok(env_bool_val) = env_val.parse::<bool>() {
+            return env_bool_val;
+        }
+    }
+    default
+}
 
 impl Params {
-    fn from_args() -> Params {
-        <Params as StructOpt>::from_args()
-    }
-    pub fn get_args() -> Params {
-        let mut args = Params::from_args();
-        args.max_unmatched = get_int_default(args.max_unmatched as u32, &String::from("DBDIFF_MAX_UNMATCHED"), 1048576) as usize;
-        args.output_format = get_str_default(&args.output_format, &String::from("DBDIFF_OUTPUT_FORMAT"), &String::from("hashmap"));
-        args.source_table_name = get_str_default(&args.source_table_name, &String::from("DBDIFF_SOURCE_TABLE_NAME"), &String::from("t1"));
-        args.dest_table_name = get_str_default(&args.dest_table_name, &String::from("DBDIFF_DESTINATION_TABLE_NAME"), &args.source_table_name);
-        args.source_query = get_str_default(&args.source_query, &String::from("DBDIFF_SOURCE_QUERY"), &String::from("select * from pg_tables"));
-        args.dest_query = get_str_default(&args.dest_query, &String::from("DBDIFF_DESTINATION_QUERY"), &args.source_query);
+    /// Whether a whole schema or a list of tables is compared, instead of a single query or table
+    pub fn multi_table(&self) -> bool {
+        !self.schema.is_empty()
+    }
+
+    /// The directory to spill unmatched rows to, if they may be spilled
+    pub fn spill_path(&self) -> Option<PathBuf> {
+        if !self.spill_dir.is_empty() {
+            Some(PathBuf::from(&self.spill_dir))
+        } else if self.max_memory > 0 {
+            Some(env::temp_dir())
+        } else {
+            None
+        }
+    }
+
+    /// Whether both sides are read in a (possibly imported) snapshot
+    pub fn snapshot_reads(&self) -> bool {
+        self.snapshot || !self.source_snapshot.is_empty() || !self.dest_snapshot.is_empty()
+    }
+
+    /// Parse the command line, and return which arguments were given along with it.
+    /// Invalid arguments exit with exit::ERROR, so that they can't be mistaken for differences.
+    fn from_args() -> (Params, ArgMatches<'static>) {
+        match Params::clap().get_matches_from_safe(env::args()) {
+            Ok(matches) => (Params::from_clap(&matches), matches),
+            Err(e) if e.use_stderr() => {
+                eprintln!("{}", e.message);
+                std::process::exit(exit::ERROR as i32);
+            },
+            // --help and --version
+            Err(e) => e.exit(),
+        }
+    }
+    /// Resolve the settings: each setting comes from the command line, then the DBDIFF_* environment
+    /// variables, then the job file (--job or DBDIFF_JOB), then the default.
+    /// Returns the settings, and the settings per pair when the job file has multiple table or query pairs.
+    /// A table or query from the command line or the environment replaces the pairs of the job file.
+    pub fn get_args() -> Result<(Params, Vec<(String, Params)>)> {
+        let (cli_args, matches) = Params::from_args();
+        let job_file = get_str_default(&cli_args.job, "DBDIFF_JOB", "");
+        let job = if job_file.is_empty() { job::Job::default() } else { job::Job::read(&job_file)? };
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        let pairs_replaced = !cli_args.source_table_name.is_empty() || !cli_args.source_query.is_empty() ||
+            env::var("DBDIFF_SOURCE_TABLE_NAME").is_ok() || env::var("DBDIFF_SOURCE_QUERY").is_ok();
+        let pairs: &[job::Pair] = if pairs_replaced { &[] } else { &job.compare };
+        match pairs {
+            [] => Ok((cli_args.resolve(&given, &job, &job.options), Vec::new())),
+            [pair] => Ok((cli_args.resolve(&given, &job, &job.options.with_pair(pair)), Vec::new())),
+            pairs => {
+                let comparisons = pairs.iter().enumerate()
+                    .map(|(n, pair)| (pair.name(n), cli_args.clone().resolve(&given, &job, &job.options.with_pair(pair))))
+                    .collect();
+                Ok((cli_args.resolve(&given, &job, &job.options), comparisons))
+            },
+        }
+    }
+
+    /// Fill in the settings that are not on the command line from the environment, the job file or the defaults
+    fn resolve(self, given: &dyn Fn(&str) -> bool, job: &job::Job, options: &job::Options) -> Params {
+        let mut args = self;
+        // Numbers are only taken from the command line when they were given (by kebab-case name), so that 0 can be given
+        let int = |name: &str, val: usize| if given(name) { Some(val as u32) } else { None };
+        let job_str = |val: &Option<String>, default: &str| val.clone().unwrap_or_else(|| String::from(default));
+        let job_list = |val: &Option<Vec<String>>| val.clone().unwrap_or_default();
+        // A flag is only taken from the command line when it or its --no- counterpart was given
+        let flag = |on: bool, off: bool| if on { Some(true) } else if off { Some(false) } else { None };
+        args.max_unmatched = get_int_default(int("max-unmatched", args.max_unmatched), &String::from("DBDIFF_MAX_UNMATCHED"),
+                                             options.max_unmatched.unwrap_or(1048576) as u32) as usize;
+        args.spill_dir = get_str_default(&args.spill_dir, &String::from("DBDIFF_SPILL_DIR"), &job_str(&options.spill_dir, ""));
+        args.max_memory = get_int_default(int("max-memory", args.max_memory), &String::from("DBDIFF_MAX_MEMORY"),
+                                          options.max_memory.unwrap_or(0) as u32) as usize;
+        args.output_format = get_str_default(&args.output_format, &String::from("DBDIFF_OUTPUT_FORMAT"), &job_str(&options.format, "hashmap"));
+        args.source_table_name = get_str_default(&args.source_table_name, &String::from("DBDIFF_SOURCE_TABLE_NAME"), &job_str(&options.source_table, ""));
+        args.source_query = get_str_default(&args.source_query, &String::from("DBDIFF_SOURCE_QUERY"), &job_str(&options.source_query, ""));
+        // Without a source query, the queries are built from the table names
+        if args.source_query.is_empty() && args.source_table_name.is_empty() {
+            args.source_query = String::from("select * from pg_tables");
+        }
+        if args.source_table_name.is_empty() {
+            args.source_table_name = String::from("t1");
+        }
+        let dest_table_name = options.dest_table.clone().unwrap_or_else(|| args.source_table_name.clone());
+        args.dest_table_name = get_str_default(&args.dest_table_name, &String::from("DBDIFF_DESTINATION_TABLE_NAME"), &dest_table_name);
+        let dest_query = options.dest_query.clone().unwrap_or_else(|| args.source_query.clone());
+        args.dest_query = get_str_default(&args.dest_query, &String::from("DBDIFF_DESTINATION_QUERY"), &dest_query);
+        args.columns = get_list_default(args.columns, &String::from("DBDIFF_COLUMNS"), &job_list(&options.columns));
+        args.exclude_columns = get_list_default(args.exclude_columns, &String::from("DBDIFF_EXCLUDE_COLUMNS"), &job_list(&options.exclude_columns));
+        args.tables = get_list_default(args.tables, &String::from("DBDIFF_TABLES"), &job_list(&options.tables));
+        let default_schema = if args.tables.is_empty() { "" } else { "public" };
+        args.schema = get_str_default(&args.schema, &String::from("DBDIFF_SCHEMA"), &job_str(&options.schema, default_schema));
+        let dest_schema = options.dest_schema.clone().unwrap_or_else(|| args.schema.clone());
+        args.dest_schema = get_str_default(&args.dest_schema, &String::from("DBDIFF_DESTINATION_SCHEMA"), &dest_schema);
+        args.parallel = get_int_default(int("parallel", args.parallel), &String::from("DBDIFF_PARALLEL"),
+                                        options.parallel.unwrap_or(4) as u32) as usize;
+        args.chunks = get_int_default(int("chunks", args.chunks), &String::from("DBDIFF_CHUNKS"),
+                                      options.chunks.unwrap_or(1) as u32) as usize;
+        args.chunk_by = get_str_default(&args.chunk_by, &String::from("DBDIFF_CHUNK_BY"), &job_str(&options.chunk_by, "auto"));
+        args.checksum = get_bool_default(flag(args.checksum, args.no_checksum), &String::from("DBDIFF_CHECKSUM"), options.checksum.unwrap_or(false));
+        args.checksum_rows = get_int_default(int("checksum-rows", args.checksum_rows), &String::from("DBDIFF_CHECKSUM_ROWS"),
+                                             options.checksum_rows.unwrap_or(10000) as u32) as usize;
+        args.where_clause = get_str_default(&args.where_clause, &String::from("DBDIFF_WHERE"), &job_str(&options.where_clause, ""));
         args.source_dsn = get_str_default(
             &args.source_dsn,
             &String::from("DBDIFF_SOURCE"),
-            &String::from("host=/tmp"),
+            &job_str(&job.source.dsn, ""),
         );
+        let dest_dsn = job.destination.dsn.clone().unwrap_or_else(|| args.source_dsn.clone());
         args.dest_dsn = get_str_default(
             &args.dest_dsn,
             &String::from("DBDIFF_DESTINATION"),
-            &args.source_dsn[..],
+            &dest_dsn,
         );
+        let (source, dest) = (&job.source, &job.destination);
+        args.source_sslmode = get_str_default(&args.source_sslmode, &String::from("DBDIFF_SOURCE_SSLMODE"), &job_str(&source.sslmode, ""));
+        args.source_sslrootcert = get_str_default(&args.source_sslrootcert, &String::from("DBDIFF_SOURCE_SSLROOTCERT"), &job_str(&source.sslrootcert, ""));
+        args.source_sslcert = get_str_default(&args.source_sslcert, &String::from("DBDIFF_SOURCE_SSLCERT"), &job_str(&source.sslcert, ""));
+        args.source_sslkey = get_str_default(&args.source_sslkey, &String::from("DBDIFF_SOURCE_SSLKEY"), &job_str(&source.sslkey, ""));
+        args.dest_sslmode = get_str_default(&args.dest_sslmode, &String::from("DBDIFF_DESTINATION_SSLMODE"), &job_str(&dest.sslmode, ""));
+        args.dest_sslrootcert = get_str_default(&args.dest_sslrootcert, &String::from("DBDIFF_DESTINATION_SSLROOTCERT"), &job_str(&dest.sslrootcert, ""));
+        args.dest_sslcert = get_str_default(&args.dest_sslcert, &String::from("DBDIFF_DESTINATION_SSLCERT"), &job_str(&dest.sslcert, ""));
+        args.dest_sslkey = get_str_default(&args.dest_sslkey, &String::from("DBDIFF_DESTINATION_SSLKEY"), &job_str(&dest.sslkey, ""));
+        args.source_password_file = get_str_default(&args.source_password_file, &String::from("DBDIFF_SOURCE_PASSWORD_FILE"), &job_str(&source.password_file, ""));
+        args.source_password_command = get_str_default(&args.source_password_command, &String::from("DBDIFF_SOURCE_PASSWORD_COMMAND"), &job_str(&source.password_command, ""));
+        args.dest_password_file = get_str_default(&args.dest_password_file, &String::from("DBDIFF_DESTINATION_PASSWORD_FILE"), &job_str(&dest.password_file, ""));
+        args.dest_password_command = get_str_default(&args.dest_password_command, &String::from("DBDIFF_DESTINATION_PASSWORD_COMMAND"), &job_str(&dest.password_command, ""));
+        args.snapshot = get_bool_default(flag(args.snapshot, args.no_snapshot), &String::from("DBDIFF_SNAPSHOT"), options.snapshot.unwrap_or(false));
+        args.source_snapshot = get_str_default(&args.source_snapshot, &String::from("DBDIFF_SOURCE_SNAPSHOT"), &job_str(&source.snapshot, ""));
+        args.dest_snapshot = get_str_default(&args.dest_snapshot, &String::from("DBDIFF_DESTINATION_SNAPSHOT"), &job_str(&dest.snapshot, ""));
+        args.key_columns = get_list_default(args.key_columns, &String::from("DBDIFF_KEY"), &job_list(&options.key));
+        args.reverse = get_bool_default(flag(args.reverse, args.no_reverse), &String::from("DBDIFF_REVERSE"), options.reverse.unwrap_or(false));
+        args.fingerprint = get_str_default(&args.fingerprint, &String::from("DBDIFF_FINGERPRINT"), &job_str(&options.fingerprint, "xxh3"));
+        args.unsupported_types = get_str_default(&args.unsupported_types, &String::from("DBDIFF_UNSUPPORTED_TYPES"), &job_str(&options.unsupported_types, "error"));
+        args.decode_errors = get_str_default(&args.decode_errors, &String::from("DBDIFF_DECODE_ERRORS"), &job_str(&options.decode_errors, "abort"));
+        args.sorted = get_bool_default(flag(args.sorted, args.no_sorted), &String::from("DBDIFF_SORTED"), options.sorted.unwrap_or(false));
+        args.trim_numeric_scale = get_bool_default(flag(args.trim_numeric_scale, args.no_trim_numeric_scale), &String::from("DBDIFF_TRIM_NUMERIC_SCALE"), options.trim_numeric_scale.unwrap_or(false));
+        args.normalize = get_list_default(args.normalize, &String::from("DBDIFF_NORMALIZE"), &job_list(&options.normalize));
+        args.strict_types = get_bool_default(flag(args.strict_types, args.no_strict_types), &String::from("DBDIFF_STRICT_TYPES"), options.strict_types.unwrap_or(false));
+        args.job = get_str_default(&args.job, &String::from("DBDIFF_JOB"), "");
         args
     }
+
+    /// The options to fingerprint and compare rows with
+    pub fn hash_options(&self) -> Result<pg_hasher::HashOptions> {
+        Ok(pg_hasher::HashOptions {
+            fingerprint: self.fingerprint.parse()?,
+            trim_numeric_scale: self.trim_numeric_scale,
+            normalize: self.normalize.iter().map(|rule| rule.parse()).collect::<Result<_>>()?,
+            strict_types: self.strict_types,
+            decode_errors: self.decode_errors.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str], job: &job::Job) -> Params {
+        let matches = Params::clap().get_matches_from_safe(std::iter::once("dbdiff").chain(args.iter().copied())).unwrap();
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        Params::from_clap(&matches).resolve(&given, job, &job.options)
+    }
+
+    #[test]
+    fn precedence() {
+        let job = job::Job {
+            options: job::Options { sorted: Some(true), snapshot: Some(true), chunks: Some(3), format: Some(String::from("json")), ..Default::default() },
+            ..Default::default()
+        };
+        // The job file over the default
+        let args = resolve(&[], &job);
+        assert!(args.sorted && args.snapshot && !args.strict_types);
+        assert_eq!((args.chunks, args.output_format.as_str(), args.parallel), (3, "json", 4));
+        // The command line over the job file, which --no- turns booleans off in
+        let args = resolve(&["--no-sorted", "--chunks", "0", "-f", "sync", "--strict-types"], &job);
+        assert!(!args.sorted && args.snapshot && args.strict_types);
+        assert_eq!((args.chunks, args.output_format.as_str()), (0, "sync"));
+        // The last of a flag and its --no- counterpart
+        assert!(resolve(&["--no-sorted", "--sorted"], &job).sorted);
+
+        // The environment over the job file, and the command line over the environment.
+        // The variables are only set in this test, so that other tests don't see them
+        env::set_var("DBDIFF_SORTED", "false");
+        env::set_var("DBDIFF_CHUNKS", "5");
+        let from_env = resolve(&[], &job);
+        let from_args = resolve(&["--sorted", "--chunks", "2"], &job);
+        env::remove_var("DBDIFF_SORTED");
+        env::remove_var("DBDIFF_CHUNKS");
+        assert_eq!((from_env.sorted, from_env.chunks), (false, 5));
+        assert_eq!((from_args.sorted, from_args.chunks), (true, 2));
+    }
 }
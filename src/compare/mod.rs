@@ -0,0 +1,323 @@
+use core::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use tokio_postgres::{Client, RowStream};
+use crate::cli;
+use crate::differ;
+use crate::exit::ErrorKind;
+use crate::pg_hasher;
+use crate::query;
+use crate::spill::SpillStore;
+
+/// The differences a comparison found
+pub enum Differences {
+    /// Differences of a comparison with key columns
+    Keyed(Vec<differ::RowDiff>),
+    /// Unmatched rows of a comparison without key columns, with their number of copies
+    Unkeyed { source: Vec<(pg_hasher::Record, usize)>, dest: Vec<(pg_hasher::Record, usize)> },
+}
+
+impl Differences {
+    /// The number of differences, counting every copy of a duplicate row
+    pub fn len(&self) -> usize {
+        match self {
+            Differences::Keyed(diffs) => diffs.len(),
+            Differences::Unkeyed { source, dest } =>
+                source.iter().chain(dest.iter()).map(|(_row, copies)| copies).sum(),
+        }
+    }
+}
+
+pub struct Comparison {
+    /// The number of rows read from the source
+    pub source_rows: u64,
+    /// The number of rows read from the destination
+    pub dest_rows: u64,
+    /// The comparison stopped at --max-unmatched unmatched rows, so there can be more differences
+    pub truncated: bool,
+    pub differences: Differences,
+}
+
+/// A single difference, to print the differences as they are found
+pub enum Difference {
+    Keyed(differ::RowDiff),
+    /// An unmatched row of a comparison without key columns, with whether it is on the source and its number of copies
+    Unkeyed { is_source: bool, row: pg_hasher::Record, copies: usize },
+}
+
+/// The rows that did not match in a comparison by fingerprint
+pub struct Unmatched {
+    pub source_rows: u64,
+    pub dest_rows: u64,
+    pub truncated: bool,
+    pub source: differ::UnmatchedRows,
+    pub dest: differ::UnmatchedRows,
+    /// The unmatched rows that were moved to disk, if any
+    pub spilled: Option<SpillStore>,
+}
+
+impl Unmatched {
+    /// All unmatched rows of both sides in memory, including the rows that were spilled
+    pub fn into_rows(self) -> Result<(differ::UnmatchedRows, differ::UnmatchedRows)> {
+        match self.spilled {
+            Some(store) => store.finish(self.source, self.dest),
+            None => Ok((self.source, self.dest)),
+        }
+    }
+}
+
+/// The unmatched rows that comparisons sharing args.max_unmatched, like the chunks of a table, hold together
+#[derive(Default)]
+pub struct SharedUnmatched(AtomicUsize);
+
+/// The unmatched rows one comparison holds, reserved in a SharedUnmatched while it runs.
+/// They are released when the comparison is done, or stops because there are too many.
+struct Reservation<'a> {
+    shared: Option<&'a SharedUnmatched>,
+    reserved: usize,
+}
+
+impl<'a> Reservation<'a> {
+    fn new(shared: Option<&'a SharedUnmatched>) -> Reservation<'a> {
+        Reservation { shared, reserved: 0 }
+    }
+
+    /// Reserve the unmatched rows the comparison holds now, and return whether it should stop or spill them:
+    /// when it holds any, and together with the other comparisons more than max
+    fn over(&mut self, unmatched: usize, max: usize) -> bool {
+        let total = match self.shared {
+            Some(shared) => {
+                if unmatched != self.reserved {
+                    shared.0.fetch_add(unmatched, Ordering::Relaxed);
+                    shared.0.fetch_sub(self.reserved, Ordering::Relaxed);
+                    self.reserved = unmatched;
+                }
+                shared.0.load(Ordering::Relaxed)
+            },
+            None => unmatched,
+        };
+        unmatched > 0 && total > max
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared {
+            shared.0.fetch_sub(self.reserved, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn next_hash(mut rows: Pin<&mut RowStream>, decoder: &mut pg_hasher::RowDecoder,
+                   hash_options: &pg_hasher::HashOptions) -> Result<(pg_hasher::Record, u128)> {
+    match rows.try_next().await {
+        Ok(or) => {
+            match or {
+                Some(r) => {
+                    let record = decoder.decode(&r).context(ErrorKind::Query)?;
+                    let hash = pg_hasher::row_hasher(&record, hash_options);
+                    Ok((record, hash))
+                },
+                None => Err(anyhow::Error::msg("We reached the end of the RowStream")),
+            }
+        },
+        Err(e) => Err(anyhow::Error::from(e).context(ErrorKind::Query)),
+    }
+}
+
+async fn query_stream(client: &Client, query: &str, table_name: &str, side: &str, args: &cli::Params,
+                      hash_options: &pg_hasher::HashOptions,
+                      unsupported_types: query::UnsupportedTypes) -> Result<(RowStream, Vec<String>)> {
+    // We need to pass in params, and we need to define params for async operations
+    // Lets define as array of 32 bit integer with 0 elements
+    let params:&[i32] = &[];
+    let query = if query.is_empty() {
+        query::build_table_query(client, table_name, args).await.context(ErrorKind::Query)?
+    } else {
+        String::from(query)
+    };
+    // Check the result columns before running the query
+    let statement = client.prepare(&query).await.context(ErrorKind::Query)?;
+    let (query, warnings) = query::check_column_types(
+        &query, side, statement.columns(), &args.key_columns, unsupported_types).context(ErrorKind::Type)?;
+    pg_hasher::normalize::check(&hash_options.normalize, side, statement.columns())?;
+    if args.sorted {
+        pg_hasher::normalize::check_sorted_keys(&hash_options.normalize, side, statement.columns(), &args.key_columns)?;
+    }
+    let rows = match query {
+        Some(q) => client.query_raw(q.as_str(), params).await,
+        None => client.query_raw(&statement, params).await,
+    }.context(ErrorKind::Query)?;
+    Ok((rows, warnings))
+}
+
+/// Run the source and destination queries (built from the table names when there is no query),
+/// and return the row streams and the warnings for the report
+pub async fn query_streams(source: &Client, dest: &Client, args: &cli::Params, hash_options: &pg_hasher::HashOptions,
+                           unsupported_types: query::UnsupportedTypes) -> Result<(RowStream, RowStream, Vec<String>)> {
+    let (source_rows, mut warnings) = query_stream(
+        source, &args.source_query, &args.source_table_name, "source", args, hash_options, unsupported_types).await?;
+    let (dest_rows, dest_warnings) = query_stream(
+        dest, &args.dest_query, &args.dest_table_name, "destination", args, hash_options, unsupported_types).await?;
+    warnings.extend(dest_warnings);
+    Ok((source_rows, dest_rows, warnings))
+}
+
+/// Compare two row streams by fingerprint, reading them alternately and keeping the rows that
+/// did not match, until there are more than args.max_unmatched of them.
+/// When unmatched rows may be spilled (see cli::Params::spill_path), they are moved to disk instead,
+/// and also when they take more than args.max_memory MB. The spilled rows are matched at the end,
+/// see Unmatched::into_rows and stream_differences.
+/// Comparisons that share args.max_unmatched, like the chunks of a table, count their unmatched rows in shared.
+pub async fn hash_unmatched(mut source_rows: Pin<&mut RowStream>, mut dest_rows: Pin<&mut RowStream>,
+                            args: &cli::Params, hash_options: &pg_hasher::HashOptions,
+                            shared: Option<&SharedUnmatched>) -> Result<Unmatched> {
+    let mut source_done: bool = false;
+    let mut dest_done: bool = false;
+    let mut i: u32 = 0;
+    let mut _of: bool = false;
+    let mut source_read: u64 = 0;
+    let mut dest_read: u64 = 0;
+    let mut source_distinct_rows = differ::UnmatchedRows::new();
+    let mut dest_distinct_rows = differ::UnmatchedRows::new();
+    let mut source_decoder = pg_hasher::RowDecoder::new("source", hash_options);
+    let mut dest_decoder = pg_hasher::RowDecoder::new("destination", hash_options);
+    let spill_path = args.spill_path();
+    let mut spill_store: Option<SpillStore> = None;
+    let mut truncated: bool = false;
+    let mut reservation = Reservation::new(shared);
+    loop {
+        if source_done && dest_done {
+            break
+        }
+        let over_memory = args.max_memory > 0 &&
+            source_distinct_rows.memory() + dest_distinct_rows.memory() > args.max_memory * 1024 * 1024;
+        let over_limit = reservation.over(dest_distinct_rows.len() + source_distinct_rows.len(), args.max_unmatched);
+        if over_memory || over_limit {
+            match &spill_path {
+                Some(path) => spill_store.get_or_insert_with(|| SpillStore::new(path.clone(), args.max_unmatched, args.max_memory * 1024 * 1024))
+                    .spill(&mut source_distinct_rows, &mut dest_distinct_rows)?,
+                None => {
+                    truncated = true;
+                    break
+                },
+            }
+        }
+        if i.is_multiple_of(2) {
+            if source_done {
+                // Add one, don't care about overflow
+                (i, _of) = i.overflowing_add(1);
+            } else {
+                match next_hash(source_rows.as_mut(), &mut source_decoder, hash_options).await {
+                    Ok((r, h)) => {
+                        source_read += 1;
+                        if !dest_distinct_rows.take(h) {
+                            source_distinct_rows.add_record(h, r, 1);
+                            (i, _of) = i.overflowing_add(1);
+                        }
+                    }
+                    Err(e) => if e.to_string() == "We reached the end of the RowStream" {
+                        source_done = true
+                    } else {
+                        return Err(e)
+                    }
+                }
+            }
+
+        } else {
+            if dest_done {
+                // Add one, don't care about overflow
+                (i, _of) = i.overflowing_add(1);
+            } else {
+                match next_hash(dest_rows.as_mut(), &mut dest_decoder, hash_options).await{
+                    Ok((r, h)) => {
+                        dest_read += 1;
+                        if !source_distinct_rows.take(h) {
+                            dest_distinct_rows.add_record(h, r, 1);
+                            (i, _of) = i.overflowing_add(1);
+                        }
+                    }
+                    Err(e) => if e.to_string() == "We reached the end of the RowStream" {
+                        dest_done = true
+                    } else {
+                        return Err(e)
+                    }
+                }
+            }
+        }
+    }
+    Ok(Unmatched {
+        source_rows: source_read,
+        dest_rows: dest_read,
+        truncated,
+        source: source_distinct_rows,
+        dest: dest_distinct_rows,
+        spilled: spill_store,
+    })
+}
+
+/// The differences between the unmatched rows of both sides.
+/// With key columns the unmatched rows are paired by key.
+pub fn differences(args: &cli::Params, hash_options: &pg_hasher::HashOptions, source_rows: differ::UnmatchedRows,
+                   dest_rows: differ::UnmatchedRows) -> Result<Differences> {
+    if args.key_columns.is_empty() {
+        return Ok(Differences::Unkeyed {
+            source: source_rows.into_counted_rows(),
+            dest: dest_rows.into_counted_rows(),
+        });
+    }
+    Ok(Differences::Keyed(differ::key_diff(&args.key_columns, hash_options, source_rows.into_rows(), dest_rows.into_rows())?))
+}
+
+/// Hand the differences between the unmatched rows of both sides to emit one at a time.
+/// Spilled rows are read back from disk as they are paired, so that they are never all in memory.
+pub fn stream_differences(args: &cli::Params, hash_options: &pg_hasher::HashOptions, unmatched: Unmatched,
+                          mut emit: impl FnMut(Difference) -> Result<()>) -> Result<()> {
+    match (unmatched.spilled, args.key_columns.is_empty()) {
+        (Some(store), true) => store.finish_unkeyed(unmatched.source, unmatched.dest, |is_source, row, copies| {
+            emit(Difference::Unkeyed { is_source, row, copies })
+        }),
+        (Some(store), false) => store.finish_keyed(
+            unmatched.source, unmatched.dest, &args.key_columns, hash_options, |diff| emit(Difference::Keyed(diff))),
+        (None, _) => match differences(args, hash_options, unmatched.source, unmatched.dest)? {
+            Differences::Keyed(diffs) => diffs.into_iter().try_for_each(|diff| emit(Difference::Keyed(diff))),
+            Differences::Unkeyed { source, dest } => source.into_iter().map(|row| (true, row))
+                .chain(dest.into_iter().map(|row| (false, row)))
+                .try_for_each(|(is_source, (row, copies))| emit(Difference::Unkeyed { is_source, row, copies })),
+        },
+    }
+}
+
+/// Compare two row streams by fingerprint, see hash_unmatched
+pub async fn hash_diff(source_rows: Pin<&mut RowStream>, dest_rows: Pin<&mut RowStream>,
+                       args: &cli::Params, hash_options: &pg_hasher::HashOptions) -> Result<Comparison> {
+    let unmatched = hash_unmatched(source_rows, dest_rows, args, hash_options, None).await?;
+    let (source_rows, dest_rows, truncated) = (unmatched.source_rows, unmatched.dest_rows, unmatched.truncated);
+    let (source, dest) = unmatched.into_rows()?;
+    Ok(Comparison { source_rows, dest_rows, truncated, differences: differences(args, hash_options, source, dest)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_limit() {
+        let shared = SharedUnmatched::default();
+        let mut first = Reservation::new(Some(&shared));
+        let mut second = Reservation::new(Some(&shared));
+        assert!(!first.over(6, 10));
+        assert!(second.over(5, 10));
+        // A comparison that holds no unmatched rows goes on
+        assert!(!Reservation::new(Some(&shared)).over(0, 10));
+        assert!(!first.over(5, 10));
+        // The rows of a comparison that is done are released
+        drop(first);
+        assert!(!second.over(10, 10));
+        assert!(second.over(11, 10));
+        drop(second);
+        assert_eq!(shared.0.load(Ordering::Relaxed), 0);
+        assert!(Reservation::new(None).over(11, 10));
+    }
+}
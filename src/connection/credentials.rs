@@ -0,0 +1,217 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use anyhow::{Context, Result};
+
+/// The PG* environment variables, like libpq reads them, with the connection setting they provide
+const ENV_SETTINGS: [(&str, &str); 14] = [
+    ("PGHOST", "host"),
+    ("PGPORT", "port"),
+    ("PGDATABASE", "dbname"),
+    ("PGUSER", "user"),
+    ("PGPASSWORD", "password"),
+    ("PGPASSFILE", "passfile"),
+    ("PGAPPNAME", "application_name"),
+    ("PGCONNECT_TIMEOUT", "connect_timeout"),
+    ("PGOPTIONS", "options"),
+    ("PGSSLMODE", "sslmode"),
+    ("PGSSLROOTCERT", "sslrootcert"),
+    ("PGSSLCERT", "sslcert"),
+    ("PGSSLKEY", "sslkey"),
+    ("PGSERVICE", "service"),
+];
+
+/// The connection settings from the PG* environment variables that are set
+pub fn env_settings() -> Vec<(String, String)> {
+    ENV_SETTINGS.iter()
+        .filter_map(|(var, key)| env::var(var).ok().map(|val| (String::from(*key), val)))
+        .filter(|(_key, val)| !val.is_empty())
+        .collect()
+}
+
+pub fn home_file(name: &str) -> Option<PathBuf> {
+    env::var("HOME").ok().map(|home| PathBuf::from(home).join(name))
+}
+
+/// The settings of a service in a service file, or None when the file or the service does not exist
+fn read_service(path: &PathBuf, service: &str) -> Result<Option<Vec<(String, String)>>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
+    };
+    let mut settings: Option<Vec<(String, String)>> = None;
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            if settings.is_some() {
+                break;
+            }
+            if &line[1..line.len() - 1] == service {
+                settings = Some(Vec::new());
+            }
+            continue;
+        }
+        if let Some(settings) = settings.as_mut() {
+            let (key, val) = line.split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Syntax error in service file {}, line {}", path.display(), n + 1))?;
+            settings.push((String::from(key.trim()), String::from(val.trim())));
+        }
+    }
+    Ok(settings)
+}
+
+/// The directory of the system wide pg_service.conf: PGSYSCONFDIR, or the one libpq was built with, which
+/// `pg_config --sysconfdir` prints. Without pg_config, /etc/postgresql-common, where Debian and Ubuntu put it
+fn sysconfdir() -> String {
+    if let Ok(dir) = env::var("PGSYSCONFDIR") {
+        return dir;
+    }
+    Command::new("pg_config").arg("--sysconfdir").output().ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|dir| String::from(dir.trim()))
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| String::from("/etc/postgresql-common"))
+}
+
+/// The settings of a service from pg_service.conf: the file in PGSERVICEFILE (or ~/.pg_service.conf),
+/// then pg_service.conf in the system configuration directory (see sysconfdir)
+pub fn service_settings(service: &str) -> Result<Vec<(String, String)>> {
+    let user_file = env::var("PGSERVICEFILE").ok().map(PathBuf::from).or_else(|| home_file(".pg_service.conf"));
+    let sysconfdir = sysconfdir();
+    let system_file = PathBuf::from(sysconfdir).join("pg_service.conf");
+    for path in user_file.iter().chain(std::iter::once(&system_file)) {
+        if let Some(settings) = read_service(path, service)? {
+            return Ok(settings);
+        }
+    }
+    Err(anyhow::anyhow!("Definition of service {} not found", service))
+}
+
+/// Split a .pgpass line in its fields, where \: and \\ are escapes
+fn pgpass_fields(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => if let Some(escaped) = chars.next() {
+                fields.last_mut().unwrap().push(escaped);
+            },
+            ':' if fields.len() < 5 => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Look up the password in a password file (passfile, PGPASSFILE or ~/.pgpass) in the format of libpq:
+/// hostname:port:database:username:password, where * matches anything.
+/// Like libpq, a file that others can read is ignored. Unix socket directories match localhost.
+pub fn pgpass_password(passfile: Option<&str>, host: &str, port: u16, dbname: &str, user: &str) -> Option<String> {
+    let path = match passfile {
+        Some(passfile) => PathBuf::from(passfile),
+        None => home_file(".pgpass")?,
+    };
+    let metadata = std::fs::metadata(&path).ok()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            eprintln!("Warning: password file {} has group or world access; permissions should be u=rw (0600) or less",
+                      path.display());
+            return None;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    let port = port.to_string();
+    let contents = std::fs::read_to_string(&path).ok()?;
+    for line in contents.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields = pgpass_fields(line);
+        if fields.len() != 5 {
+            continue;
+        }
+        let matches = |field: &String, val: &str| field == "*" || field == val;
+        let host_matches = matches(&fields[0], host) || (host.starts_with('/') && fields[0] == "localhost");
+        if host_matches && matches(&fields[1], &port) && matches(&fields[2], dbname) && matches(&fields[3], user) {
+            return Some(fields[4].clone());
+        }
+    }
+    None
+}
+
+/// Read a password from the first line of a file
+pub fn password_from_file(path: &str) -> Result<String> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read password file {}", path))?;
+    Ok(String::from(contents.lines().next().unwrap_or("")))
+}
+
+/// Run a command with the shell, and use the first line it prints as the password
+pub fn password_from_command(command: &str) -> Result<String> {
+    let output = Command::new("sh").arg("-c").arg(command).output()
+        .with_context(|| format!("Could not run password command {}", command))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("Password command {} failed: {}", command,
+                                   String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let stdout = String::from_utf8(output.stdout).context("Password command did not print UTF-8")?;
+    Ok(String::from(stdout.lines().next().unwrap_or("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file of its own in the temporary directory with these contents, readable by the owner only
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dbdiff-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn pgpass_escapes() {
+        assert_eq!(pgpass_fields("db:5432:app:me:secret"), vec!["db", "5432", "app", "me", "secret"]);
+        assert_eq!(pgpass_fields("db:*:a\\:b:me:pass\\\\word"), vec!["db", "*", "a:b", "me", "pass\\word"]);
+        // Colons in the password need no escape
+        assert_eq!(pgpass_fields("db:*:*:me:a:b"), vec!["db", "*", "*", "me", "a:b"]);
+        assert_eq!(pgpass_fields("db:5432").len(), 2);
+    }
+
+    #[test]
+    fn pgpass_matching() {
+        let path = temp_file("pgpass", "# comment\ndb:5432:app:me:exact\n*:*:other:*:wildcard\nlocalhost:*:*:me:local\n");
+        let passfile = path.to_str();
+        assert_eq!(pgpass_password(passfile, "db", 5432, "app", "me").as_deref(), Some("exact"));
+        assert_eq!(pgpass_password(passfile, "elsewhere", 6543, "other", "you").as_deref(), Some("wildcard"));
+        assert_eq!(pgpass_password(passfile, "/tmp", 5432, "app", "me").as_deref(), Some("local"));
+        assert_eq!(pgpass_password(passfile, "db", 5433, "app", "you"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn services() {
+        let path = temp_file("service", "# services\n[one]\nhost = db1\n\n[two]\nhost=db2\nport = 5433\n[three]\n");
+        let pair = |key: &str, value: &str| (String::from(key), String::from(value));
+        assert_eq!(read_service(&path, "one").unwrap(), Some(vec![pair("host", "db1")]));
+        assert_eq!(read_service(&path, "two").unwrap(), Some(vec![pair("host", "db2"), pair("port", "5433")]));
+        assert_eq!(read_service(&path, "three").unwrap(), Some(Vec::new()));
+        assert_eq!(read_service(&path, "four").unwrap(), None);
+        assert_eq!(read_service(&path.with_extension("missing"), "one").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+        let path = temp_file("invalid-service", "[one]\nhost\n");
+        assert!(read_service(&path, "one").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,543 @@
+mod credentials;
+
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use postgres_openssl::MakeTlsConnector;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::config::Host;
+use tokio_postgres::{Client, Config};
+use crate::cli;
+use crate::exit::ErrorKind;
+
+/// TLS settings of a connection, like libpq's sslmode, sslrootcert, sslcert and sslkey
+#[derive(Default, Clone)]
+pub struct TlsSettings {
+    pub sslmode: String,
+    pub sslrootcert: String,
+    pub sslcert: String,
+    pub sslkey: String,
+}
+
+impl TlsSettings {
+    /// Set a TLS setting from the connection string. Returns false for keys that are not TLS settings.
+    fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "sslmode" => self.sslmode = String::from(value),
+            "sslrootcert" => self.sslrootcert = String::from(value),
+            "sslcert" => self.sslcert = String::from(value),
+            "sslkey" => self.sslkey = String::from(value),
+            _ => return false,
+        }
+        true
+    }
+
+    /// These settings, with the non-empty settings of other taking precedence
+    fn overridden_by(self, other: &TlsSettings) -> TlsSettings {
+        let pick = |own: String, other: &String| if other.is_empty() { own } else { other.clone() };
+        TlsSettings {
+            sslmode: pick(self.sslmode, &other.sslmode),
+            sslrootcert: pick(self.sslrootcert, &other.sslrootcert),
+            sslcert: pick(self.sslcert, &other.sslcert),
+            sslkey: pick(self.sslkey, &other.sslkey),
+        }
+    }
+
+    /// The sslmode, which defaults to prefer like it does for libpq
+    fn mode(&self) -> &str {
+        if self.sslmode.is_empty() { "prefer" } else { &self.sslmode }
+    }
+
+    fn ssl_mode(&self) -> Result<SslMode> {
+        match self.mode() {
+            "disable" => Ok(SslMode::Disable),
+            // tokio_postgres has no allow (plain text first, then TLS), prefer is the closest
+            "allow" | "prefer" => Ok(SslMode::Prefer),
+            "require" | "verify-ca" | "verify-full" => Ok(SslMode::Require),
+            mode => Err(anyhow::anyhow!(
+                "Invalid sslmode {}, use disable, allow, prefer, require, verify-ca or verify-full", mode)),
+        }
+    }
+
+    /// These settings, with the files that libpq uses by default when they exist:
+    /// ~/.postgresql/root.crt, postgresql.crt and postgresql.key
+    fn with_default_files(self) -> TlsSettings {
+        let default_file = |name: &str| credentials::home_file(&format!(".postgresql/{}", name))
+            .filter(|path| path.exists())
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let defaults = TlsSettings {
+            sslmode: String::new(),
+            sslrootcert: default_file("root.crt"),
+            sslcert: default_file("postgresql.crt"),
+            sslkey: default_file("postgresql.key"),
+        };
+        defaults.overridden_by(&self)
+    }
+
+    /// Build the TLS connector. Like libpq, the server certificate is verified with verify-ca and verify-full,
+    /// and with require when a root certificate is given (or ~/.postgresql/root.crt exists).
+    /// Only verify-full checks the host name.
+    /// Unlike libpq, the system trust store is used when there is no root certificate, like with sslrootcert=system.
+    fn connector(&self) -> Result<MakeTlsConnector> {
+        let settings = self.clone().with_default_files();
+        let mode = settings.mode();
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if !settings.sslrootcert.is_empty() && settings.sslrootcert != "system" {
+            builder.set_ca_file(&settings.sslrootcert)
+                .map_err(|e| anyhow::anyhow!("Could not read sslrootcert {}: {}", settings.sslrootcert, e))?;
+        }
+        if !settings.sslcert.is_empty() {
+            if settings.sslkey.is_empty() {
+                return Err(anyhow::anyhow!("sslcert {} requires an sslkey", settings.sslcert));
+            }
+            builder.set_certificate_chain_file(&settings.sslcert)
+                .map_err(|e| anyhow::anyhow!("Could not read sslcert {}: {}", settings.sslcert, e))?;
+            builder.set_private_key_file(&settings.sslkey, SslFiletype::PEM)
+                .map_err(|e| anyhow::anyhow!("Could not read sslkey {}: {}", settings.sslkey, e))?;
+        }
+        let verify = mode == "verify-ca" || mode == "verify-full" || (mode == "require" && !settings.sslrootcert.is_empty());
+        builder.set_verify(if verify { SslVerifyMode::PEER } else { SslVerifyMode::NONE });
+        let mut connector = MakeTlsConnector::new(builder.build());
+        if mode != "verify-full" {
+            connector.set_callback(|config, _domain| {
+                config.set_verify_hostname(false);
+                Ok(())
+            });
+        }
+        Ok(connector)
+    }
+}
+
+/// Decode %XX escapes in a connection URI
+fn percent_decode(val: &str) -> Result<String> {
+    let bytes = val.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'%' {
+            let hex = val.get(pos + 1..pos + 3)
+                .ok_or_else(|| anyhow::anyhow!("Invalid percent encoding in connection string"))?;
+            decoded.push(u8::from_str_radix(hex, 16)?);
+            pos += 3;
+        } else {
+            decoded.push(bytes[pos]);
+            pos += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Split the key=value pairs of a connection string, where values may be single quoted
+/// with backslash escapes
+fn split_pairs(dsn: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    let mut chars = dsn.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(pairs);
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.peek() {
+            if *c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(*c);
+            chars.next();
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.next() != Some('=') {
+            return Err(anyhow::anyhow!("Missing = after {} in connection string", key));
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'\'') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some('\\') => value.push(chars.next().unwrap_or('\\')),
+                    Some(c) => value.push(c),
+                    None => return Err(anyhow::anyhow!("Unterminated quoted value for {} in connection string", key)),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                if *c == '\\' {
+                    chars.next();
+                }
+                if let Some(c) = chars.next() {
+                    value.push(c);
+                }
+            }
+        }
+        pairs.push((key, value));
+    }
+}
+
+/// Quote a value for a key=value connection string
+fn quote_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The connection string with its password replaced, to show it
+pub fn masked_dsn(dsn: &str) -> String {
+    const MASK: &str = "********";
+    if dsn.starts_with("postgres://") || dsn.starts_with("postgresql://") {
+        let (scheme, rest) = dsn.split_once("://").unwrap_or_default();
+        let (base, query) = rest.split_once('?').map_or((rest, None), |(base, query)| (base, Some(query)));
+        let (authority, path) = base.split_at(base.find('/').unwrap_or(base.len()));
+        let authority = match authority.rsplit_once('@') {
+            Some((userinfo, hosts)) => match userinfo.split_once(':') {
+                Some((user, _password)) => format!("{}:{}@{}", user, MASK, hosts),
+                None => String::from(authority),
+            },
+            None => String::from(authority),
+        };
+        let query = query.map(|query| query.split('&')
+            .map(|param| if param.starts_with("password=") { format!("password={}", MASK) } else { String::from(param) })
+            .collect::<Vec<String>>()
+            .join("&"));
+        return match query {
+            Some(query) => format!("{}://{}{}?{}", scheme, authority, path, query),
+            None => format!("{}://{}{}", scheme, authority, path),
+        };
+    }
+    match split_pairs(dsn) {
+        Ok(pairs) => pairs.iter()
+            .map(|(key, value)| if key == "password" { format!("{}={}", key, MASK) } else { format!("{}={}", key, quote_value(value)) })
+            .collect::<Vec<String>>()
+            .join(" "),
+        // Parts of an invalid connection string could still be a password
+        Err(_) => String::from(MASK),
+    }
+}
+
+/// Connection settings of a side that tokio_postgres does not handle itself
+#[derive(Default, Clone)]
+pub struct ConnectionSettings {
+    pub tls: TlsSettings,
+    /// Service name in pg_service.conf
+    pub service: String,
+    /// Password file in the format of .pgpass
+    pub passfile: String,
+    /// File with the password on its first line
+    pub password_file: String,
+    /// Command that prints the password
+    pub password_command: String,
+    /// The password the command printed, when it already ran
+    pub password: String,
+}
+
+impl ConnectionSettings {
+    /// Set a setting from the connection string. Returns false for keys that tokio_postgres handles.
+    fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "service" => self.service = String::from(value),
+            "passfile" => self.passfile = String::from(value),
+            _ => return self.tls.set(key, value),
+        }
+        true
+    }
+}
+
+/// Take the settings that tokio_postgres does not know (or only partly supports) out of a connection
+/// string (key=value or URI): the TLS settings, service and passfile
+fn split_settings(dsn: &str) -> Result<(String, ConnectionSettings)> {
+    let mut settings = ConnectionSettings::default();
+    if dsn.starts_with("postgres://") || dsn.starts_with("postgresql://") {
+        let (base, query) = match dsn.split_once('?') {
+            Some((base, query)) => (base, query),
+            None => return Ok((String::from(dsn), settings)),
+        };
+        let mut params: Vec<&str> = Vec::new();
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            if !settings.set(key, &percent_decode(value)?) {
+                params.push(param);
+            }
+        }
+        if params.is_empty() {
+            return Ok((String::from(base), settings));
+        }
+        return Ok((format!("{}?{}", base, params.join("&")), settings));
+    }
+    let mut pairs: Vec<String> = Vec::new();
+    for (key, value) in split_pairs(dsn)? {
+        if !settings.set(&key, &value) {
+            pairs.push(format!("{}={}", key, quote_value(&value)));
+        }
+    }
+    Ok((pairs.join(" "), settings))
+}
+
+/// Use a setting from a service or the environment, unless the connection string already has it
+fn set_default(config: &mut Config, settings: &mut ConnectionSettings, key: &str, value: &str) -> Result<()> {
+    match key {
+        "host" if config.get_hosts().is_empty() => {
+            for host in value.split(',') {
+                config.host(host);
+            }
+        },
+        "port" if config.get_ports().is_empty() => {
+            for port in value.split(',') {
+                config.port(port.parse().with_context(|| format!("Invalid port {}", port))?);
+            }
+        },
+        "dbname" if config.get_dbname().is_none() => { config.dbname(value); },
+        "user" if config.get_user().is_none() => { config.user(value); },
+        "password" if config.get_password().is_none() => { config.password(value); },
+        "application_name" if config.get_application_name().is_none() => { config.application_name(value); },
+        "options" if config.get_options().is_none() => { config.options(value); },
+        "connect_timeout" if config.get_connect_timeout().is_none() => {
+            let seconds: u64 = value.parse().with_context(|| format!("Invalid connect_timeout {}", value))?;
+            config.connect_timeout(Duration::from_secs(seconds));
+        },
+        "sslmode" | "sslrootcert" | "sslcert" | "sslkey" | "passfile" => {
+            let mut default = ConnectionSettings::default();
+            default.set(key, value);
+            settings.tls = default.tls.overridden_by(&settings.tls);
+            if settings.passfile.is_empty() {
+                settings.passfile = default.passfile;
+            }
+        },
+        "host" | "port" | "dbname" | "user" | "password" | "application_name" | "options" | "connect_timeout" => (),
+        _ => return Err(anyhow::anyhow!("Invalid connection setting {}", key)),
+    }
+    Ok(())
+}
+
+/// The connection settings of a side: the connection string, then the service (service in the connection
+/// string or PGSERVICE), then the PG* environment variables, like libpq.
+/// The password comes from the password file or command of the side, then from those settings,
+/// and last from the password file (passfile, PGPASSFILE or ~/.pgpass).
+/// Non-empty TLS settings of the side override all others.
+fn side_config(dsn: &str, side_settings: &ConnectionSettings) -> Result<(Config, TlsSettings)> {
+    let (dsn, mut settings) = split_settings(dsn)?;
+    let mut config: Config = dsn.parse()?;
+    if !side_settings.password_file.is_empty() && !side_settings.password_command.is_empty() {
+        return Err(anyhow::anyhow!("Use either a password file or a password command"));
+    }
+    if !side_settings.password_file.is_empty() {
+        config.password(credentials::password_from_file(&side_settings.password_file)?);
+    } else if !side_settings.password.is_empty() {
+        config.password(&side_settings.password);
+    } else if !side_settings.password_command.is_empty() {
+        config.password(credentials::password_from_command(&side_settings.password_command)?);
+    }
+
+    let env_settings = credentials::env_settings();
+    let service = match settings.service.as_str() {
+        "" => env_settings.iter().find(|(key, _val)| key == "service").map(|(_key, val)| val.clone()),
+        service => Some(String::from(service)),
+    };
+    if let Some(service) = service {
+        for (key, val) in credentials::service_settings(&service)? {
+            set_default(&mut config, &mut settings, &key, &val)
+                .with_context(|| format!("In service {}", service))?;
+        }
+    }
+    for (key, val) in env_settings.iter().filter(|(key, _val)| key != "service") {
+        set_default(&mut config, &mut settings, key, val)?;
+    }
+    if config.get_hosts().is_empty() {
+        config.host("/tmp");
+    }
+
+    if config.get_password().is_none() {
+        let host = match &config.get_hosts()[0] {
+            Host::Tcp(host) => host.clone(),
+            Host::Unix(path) => path.to_string_lossy().into_owned(),
+        };
+        let port = config.get_ports().first().copied().unwrap_or(5432);
+        let user = config.get_user().map(String::from).or_else(|| std::env::var("USER").ok()).unwrap_or_default();
+        let dbname = config.get_dbname().map(String::from).unwrap_or_else(|| user.clone());
+        let passfile = if settings.passfile.is_empty() { None } else { Some(settings.passfile.as_str()) };
+        if let Some(password) = credentials::pgpass_password(passfile, &host, port, &dbname, &user) {
+            config.password(password);
+        }
+    }
+    Ok((config, settings.tls.overridden_by(&side_settings.tls)))
+}
+
+/// Connect to a database, and run the connection on its own task
+pub async fn connect(dsn: &str, settings: &ConnectionSettings, side: &str) -> Result<Client> {
+    let (mut config, tls) = side_config(dsn, settings)
+        .with_context(|| format!("Invalid connection settings for the {} database", side))
+        .context(ErrorKind::Connection)?;
+    config.ssl_mode(tls.ssl_mode()?);
+    let (client, connection) = config.connect(tls.connector()?).await
+        .with_context(|| format!("Could not connect to the {} database", side))
+        .context(ErrorKind::Connection)?;
+
+    // The connection object performs the actual communication with the database,
+    // so spawn it off to run on its own.
+    let side = String::from(side);
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("{} connection error: {}", side, e);
+        }
+    });
+    Ok(client)
+}
+
+/// A snapshot that the connections of a side read in
+pub struct Snapshot {
+    pub side: &'static str,
+    pub id: String,
+    /// The WAL position of the server when the snapshot was exported (the replayed position on a standby),
+    /// empty for a snapshot that was passed in
+    pub lsn: String,
+}
+
+/// Start a repeatable read, read only transaction, in an exported snapshot when there is one
+async fn begin_snapshot(client: &Client, snapshot: &str) -> Result<()> {
+    client.batch_execute("begin isolation level repeatable read read only").await?;
+    if !snapshot.is_empty() {
+        client.batch_execute(&format!("set transaction snapshot '{}'", snapshot.replace('\'', "''"))).await?;
+    }
+    Ok(())
+}
+
+async fn export_snapshot(client: &Client, side: &'static str) -> Result<Snapshot> {
+    let row = client.query_one(
+        "select pg_catalog.pg_export_snapshot(), (case when pg_catalog.pg_is_in_recovery() \
+         then pg_catalog.pg_last_wal_replay_lsn() else pg_catalog.pg_current_wal_lsn() end)::text",
+        &[],
+    ).await?;
+    Ok(Snapshot { side, id: row.get(0), lsn: row.get(1) })
+}
+
+/// Export the snapshots of a pair of connections into args, so that all other connections
+/// (for chunks and tables) read in the same snapshots.
+/// The pair has to stay open until those connections are done.
+/// The WAL position is read in the same statement as the export, so every transaction that the snapshot sees
+/// committed before it, but transactions that committed just before it may not be seen. The position of a
+/// snapshot that was passed in (--source-snapshot) is unknown, as it was taken earlier.
+pub async fn share_snapshots(args: &mut cli::Params, source: &Client, dest: &Client) -> Result<Vec<Snapshot>> {
+    let mut snapshots = vec![
+        export_snapshot(source, "source").await.context(ErrorKind::Query)?,
+        export_snapshot(dest, "destination").await.context(ErrorKind::Query)?,
+    ];
+    if !args.source_snapshot.is_empty() {
+        snapshots[0].lsn = String::new();
+    }
+    if !args.dest_snapshot.is_empty() {
+        snapshots[1].lsn = String::new();
+    }
+    args.source_snapshot = snapshots[0].id.clone();
+    args.dest_snapshot = snapshots[1].id.clone();
+    args.source_snapshot_lsn = snapshots[0].lsn.clone();
+    args.dest_snapshot_lsn = snapshots[1].lsn.clone();
+    Ok(snapshots)
+}
+
+/// Run the password commands of both sides once, so that all connections (of chunks, tables and pairs)
+/// use the password they printed. Pairs with the same command share its password.
+pub fn resolve_passwords(args: &mut cli::Params, comparisons: &mut [(String, cli::Params)]) -> Result<()> {
+    let mut passwords: HashMap<String, String> = HashMap::new();
+    for args in std::iter::once(args).chain(comparisons.iter_mut().map(|(_name, args)| args)) {
+        for (command, password) in [(&args.source_password_command, &mut args.source_password),
+                                    (&args.dest_password_command, &mut args.dest_password)] {
+            if command.is_empty() {
+                continue;
+            }
+            if !passwords.contains_key(command) {
+                let resolved = credentials::password_from_command(command).context(ErrorKind::Connection)?;
+                passwords.insert(command.clone(), resolved);
+            }
+            *password = passwords[command].clone();
+        }
+    }
+    Ok(())
+}
+
+/// Connect to the source and the destination database.
+/// With snapshot reads, both connections are in a transaction in the snapshot of their side.
+pub async fn connect_pair(args: &cli::Params) -> Result<(Client, Client)> {
+    let source_settings = ConnectionSettings {
+        tls: TlsSettings {
+            sslmode: args.source_sslmode.clone(),
+            sslrootcert: args.source_sslrootcert.clone(),
+            sslcert: args.source_sslcert.clone(),
+            sslkey: args.source_sslkey.clone(),
+        },
+        password_file: args.source_password_file.clone(),
+        password_command: args.source_password_command.clone(),
+        password: args.source_password.clone(),
+        ..Default::default()
+    };
+    let dest_settings = ConnectionSettings {
+        tls: TlsSettings {
+            sslmode: args.dest_sslmode.clone(),
+            sslrootcert: args.dest_sslrootcert.clone(),
+            sslcert: args.dest_sslcert.clone(),
+            sslkey: args.dest_sslkey.clone(),
+        },
+        password_file: args.dest_password_file.clone(),
+        password_command: args.dest_password_command.clone(),
+        password: args.dest_password.clone(),
+        ..Default::default()
+    };
+    let source = connect(&args.source_dsn, &source_settings, "source").await?;
+    let dest = connect(&args.dest_dsn, &dest_settings, "destination").await?;
+    if args.snapshot_reads() {
+        begin_snapshot(&source, &args.source_snapshot).await.context(ErrorKind::Query)?;
+        begin_snapshot(&dest, &args.dest_snapshot).await.context(ErrorKind::Query)?;
+    }
+    Ok((source, dest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(dsn: &str) -> Vec<(String, String)> {
+        split_pairs(dsn).unwrap()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (String::from(key), String::from(value))
+    }
+
+    #[test]
+    fn connection_string_pairs() {
+        assert_eq!(pairs(" host=/tmp  port = 5433 "), vec![pair("host", "/tmp"), pair("port", "5433")]);
+        assert_eq!(pairs("password='it\\'s a \\\\ secret' user=me"), vec![pair("password", "it's a \\ secret"), pair("user", "me")]);
+        assert_eq!(pairs("password=''"), vec![pair("password", "")]);
+        assert_eq!(pairs("dbname=a\\ b"), vec![pair("dbname", "a b")]);
+        assert!(split_pairs("host").is_err());
+        assert!(split_pairs("password='open").is_err());
+    }
+
+    #[test]
+    fn masked_passwords() {
+        assert_eq!(masked_dsn("host=db password='it\\'s secret' user=me"), "host='db' password=******** user='me'");
+        assert_eq!(masked_dsn("postgres://me:secret@db:5432/app?sslmode=require&password=other"),
+                   "postgres://me:********@db:5432/app?sslmode=require&password=********");
+        assert_eq!(masked_dsn("postgresql://me@db/app"), "postgresql://me@db/app");
+        assert_eq!(masked_dsn("host=db password='unterminated"), "********");
+    }
+
+    #[test]
+    fn settings_taken_out() {
+        let (dsn, settings) = split_settings("host=db sslmode=verify-full service=app passfile='/my pass'").unwrap();
+        assert_eq!(dsn, "host='db'");
+        assert_eq!((settings.tls.sslmode.as_str(), settings.service.as_str(), settings.passfile.as_str()),
+                   ("verify-full", "app", "/my pass"));
+        let (dsn, settings) = split_settings("postgres://db/app?sslrootcert=%2Fca.crt&application_name=x").unwrap();
+        assert_eq!(dsn, "postgres://db/app?application_name=x");
+        assert_eq!(settings.tls.sslrootcert, "/ca.crt");
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::cmp::Ordering;
+use core::pin::Pin;
+use anyhow::{Context, Result};
+use futures::{Stream, StreamExt, TryStreamExt};
+use tokio_postgres::RowStream;
+use crate::exit::ErrorKind;
+use crate::pg_hasher;
+use crate::pg_hasher::Record;
+
+/// Rows that have not (yet) been matched by an identical row on the other side.
+/// Identical rows are counted per hash, so that tables with duplicate rows are compared as multisets.
+pub struct UnmatchedRows {
+    rows: HashMap<u128, (Record, usize)>,
+    copies: usize,
+    /// An estimate of the memory the records take
+    bytes: usize,
+}
+
+/// An estimate of the memory of a record in UnmatchedRows, including the hash map entry
+pub fn record_bytes(record: &Record) -> usize {
+    64 + record.values.iter().map(pg_hasher::Value::memory).sum::<usize>()
+}
+
+impl UnmatchedRows {
+    pub fn new() -> UnmatchedRows {
+        UnmatchedRows { rows: HashMap::new(), copies: 0, bytes: 0 }
+    }
+
+    /// The number of unmatched rows, counting every copy of a duplicate row
+    pub fn len(&self) -> usize {
+        self.copies
+    }
+
+    /// An estimate of the memory the unmatched rows take, in bytes
+    pub fn memory(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn add_record(&mut self, hash: u128, record: Record, count: usize) {
+        if let Some((_record, copies)) = self.rows.get_mut(&hash) {
+            *copies += count;
+        } else {
+            self.bytes += record_bytes(&record);
+            self.rows.insert(hash, (record, count));
+        }
+        self.copies += count;
+    }
+
+    /// Remove one copy of the row with this hash, and return false if there was none
+    pub fn take(&mut self, hash: u128) -> bool {
+        match self.rows.get_mut(&hash) {
+            Some((_row, count)) => {
+                *count -= 1;
+                if *count == 0 {
+                    if let Some((record, _count)) = self.rows.remove(&hash) {
+                        self.bytes -= record_bytes(&record);
+                    }
+                }
+                self.copies -= 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Add the unmatched rows of another part of the same side, after matching them against
+    /// the unmatched rows of the other side
+    pub fn merge(&mut self, rows: UnmatchedRows, other_side: &mut UnmatchedRows) {
+        for (hash, (record, count)) in rows.rows {
+            let mut unmatched = count;
+            while unmatched > 0 && other_side.take(hash) {
+                unmatched -= 1;
+            }
+            if unmatched > 0 {
+                self.add_record(hash, record, unmatched);
+            }
+        }
+    }
+
+    /// Take all unmatched rows out, ordered by hash, to spill them to disk
+    pub fn drain_sorted(&mut self) -> Vec<(u128, Record, usize)> {
+        let mut rows: Vec<(u128, Record, usize)> = self.rows.drain()
+            .map(|(hash, (record, count))| (hash, record, count))
+            .collect();
+        rows.sort_unstable_by_key(|(hash, _record, _count)| *hash);
+        self.copies = 0;
+        self.bytes = 0;
+        rows
+    }
+
+    /// The distinct unmatched rows, each with its number of copies
+    pub fn into_counted_rows(self) -> Vec<(Record, usize)> {
+        self.rows.into_values().collect()
+    }
+
+    /// All unmatched rows, with every copy of a duplicate row as a separate row
+    pub fn into_rows(self) -> Vec<Record> {
+        let mut rows: Vec<Record> = Vec::with_capacity(self.copies);
+        for (row, count) in self.rows.into_values() {
+            for _ in 1..count {
+                rows.push(row.clone());
+            }
+            rows.push(row);
+        }
+        rows
+    }
+}
+
+/// A column that holds a different value on the source and on the destination
+pub struct ColumnChange {
+    pub column: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The outcome of pairing an unmatched row with its counterpart (by key) on the other side
+pub enum RowDiff {
+    /// The key only exists on the destination
+    Added { row: Record },
+    /// The key only exists on the source
+    Removed { row: Record },
+    /// The key exists on both sides, but one or more columns differ
+    Changed { key: Vec<String>, source: Record, dest: Record, changes: Vec<ColumnChange> },
+}
+
+fn row_key<V: Clone>(key_columns: &[String], map: &HashMap<String, V>) -> Result<Vec<V>> {
+    let mut key: Vec<V> = Vec::new();
+    for col in key_columns {
+        match map.get(col) {
+            Some(val) => key.push(val.clone()),
+            None => return Err(anyhow::anyhow!("key column {} is not part of the query result", col)),
+        }
+    }
+    Ok(key)
+}
+
+/// The columns that differ, after normalization. The changes hold the original values.
+fn column_changes(source: &Record, source_map: &HashMap<String, String>,
+                  dest: &Record, dest_map: &HashMap<String, String>,
+                  hash_options: &pg_hasher::HashOptions) -> Vec<ColumnChange> {
+    let missing = String::from("<missing>");
+    let source_normalized = pg_hasher::normalized_row_map(source, hash_options);
+    let dest_normalized = pg_hasher::normalized_row_map(dest, hash_options);
+    let mut changes: Vec<ColumnChange> = Vec::new();
+    for col in source.columns.iter() {
+        let old = &source_map[&col.name];
+        let new = dest_map.get(&col.name).unwrap_or(&missing);
+        let (source_val, dest_val) = (source_normalized.get(&col.name), dest_normalized.get(&col.name));
+        if source_val != dest_val {
+            let (old, new) = match (source_val, dest_val) {
+                // With strict types, show the types when they differ, as the values may look the same
+                (Some((_, Some(old_type))), Some((_, Some(new_type)))) if old_type != new_type =>
+                    (format!("{}::{}", old, old_type), format!("{}::{}", new, new_type)),
+                _ => (old.clone(), new.clone()),
+            };
+            changes.push(ColumnChange { column: col.name.clone(), old, new });
+        }
+    }
+    for col in dest.columns.iter() {
+        if !source_map.contains_key(&col.name) {
+            changes.push(ColumnChange {
+                column: col.name.clone(),
+                old: missing.clone(),
+                new: dest_map[&col.name].clone(),
+            });
+        }
+    }
+    changes
+}
+
+/// The difference between the rows with the same key on both sides, if any columns differ.
+/// Columns are paired by name, so the sides may list them in a different order.
+fn paired_diff(key: Vec<String>, source: Record, source_map: &HashMap<String, String>,
+               dest: Record, dest_map: &HashMap<String, String>,
+               hash_options: &pg_hasher::HashOptions) -> Option<RowDiff> {
+    let changes = column_changes(&source, source_map, &dest, dest_map, hash_options);
+    if changes.is_empty() {
+        None
+    } else {
+        Some(RowDiff::Changed { key, source, dest, changes })
+    }
+}
+
+/// A record with its key columns in the form they are compared in, see pg_hasher::normalized_key
+pub type KeyedRecord = (Vec<pg_hasher::Value>, Record);
+
+/// The records with their normalized keys, ordered by key (see pg_hasher::normalized_key_cmp).
+/// Records with the same key keep their order.
+fn sorted_by_key(key_columns: &[String], hash_options: &pg_hasher::HashOptions, rows: Vec<Record>) -> Result<Vec<KeyedRecord>> {
+    let mut keyed = rows.into_iter()
+        .map(|row| Ok((pg_hasher::normalized_key(key_columns, &row, hash_options)?, row)))
+        .collect::<Result<Vec<KeyedRecord>>>()?;
+    keyed.sort_by(|(a, _row), (b, _other)| pg_hasher::normalized_key_cmp(a, b));
+    Ok(keyed)
+}
+
+/// Pair unmatched source and destination rows by their (normalized) key columns and classify them.
+/// The result is ordered by key, by the values of the key columns (nulls last).
+pub fn key_diff(key_columns: &[String], hash_options: &pg_hasher::HashOptions, source_rows: Vec<Record>, dest_rows: Vec<Record>) -> Result<Vec<RowDiff>> {
+    let source = sorted_by_key(key_columns, hash_options, source_rows)?;
+    let dest = sorted_by_key(key_columns, hash_options, dest_rows)?;
+    let mut diffs: Vec<RowDiff> = Vec::new();
+    pair_by_key(key_columns, hash_options, source.into_iter().map(Ok), dest.into_iter().map(Ok), |diff| {
+        diffs.push(diff);
+        Ok(())
+    })?;
+    Ok(diffs)
+}
+
+/// Pair the unmatched rows of both sides, which come in order of their normalized keys, like merge_diff does
+/// with the rows of sorted queries, and hand every difference to emit.
+/// A key that is on one side more often than on the other pairs up as often as it can.
+pub fn pair_by_key<S, D, F>(key_columns: &[String], hash_options: &pg_hasher::HashOptions, mut source_rows: S,
+                            mut dest_rows: D, mut emit: F) -> Result<()>
+    where S: Iterator<Item = Result<KeyedRecord>>, D: Iterator<Item = Result<KeyedRecord>>, F: FnMut(RowDiff) -> Result<()> {
+    let mut source = source_rows.next().transpose()?;
+    let mut dest = dest_rows.next().transpose()?;
+    loop {
+        let ord = match (&source, &dest) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((s, _)), Some((d, _))) => pg_hasher::normalized_key_cmp(s, d),
+        };
+        if ord != Ordering::Greater {
+            let next = source_rows.next().transpose()?;
+            let (_key, row) = std::mem::replace(&mut source, next).unwrap();
+            if ord == Ordering::Less {
+                emit(RowDiff::Removed { row })?;
+                continue;
+            }
+            let next = dest_rows.next().transpose()?;
+            let (_key, dest_row) = std::mem::replace(&mut dest, next).unwrap();
+            let source_map = pg_hasher::row_map(&row);
+            let dest_map = pg_hasher::row_map(&dest_row);
+            let key = row_key(key_columns, &source_map)?;
+            if let Some(diff) = paired_diff(key, row, &source_map, dest_row, &dest_map, hash_options) {
+                emit(diff)?;
+            }
+        } else {
+            let next = dest_rows.next().transpose()?;
+            let (_key, row) = std::mem::replace(&mut dest, next).unwrap();
+            emit(RowDiff::Added { row })?;
+        }
+    }
+    Ok(())
+}
+
+/// The next row of a sorted query with its normalized key, after checking that it does not come before the current row
+async fn next_sorted_row<S>(rows: &mut S, current: &Option<KeyedRecord>, key_columns: &[String],
+                            hash_options: &pg_hasher::HashOptions, side: &str) -> Result<Option<KeyedRecord>>
+    where S: Stream<Item = Result<Record>> + Unpin {
+    match rows.try_next().await? {
+        Some(next) => {
+            let key = pg_hasher::normalized_key(key_columns, &next, hash_options)?;
+            if let Some((current_key, _row)) = current {
+                if pg_hasher::normalized_key_order(current_key, &key) == Ordering::Greater {
+                    return Err(anyhow::anyhow!("The {} query is not ordered by the key columns", side));
+                }
+            }
+            Ok(Some((key, next)))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Walk two RowStreams that are both ordered by the key columns in lockstep (a merge join),
+/// and hand every difference to emit as soon as it is found.
+/// Only the current row of each side is kept in memory, no matter how far the tables diverge.
+/// Returns the number of rows read from the source and the destination.
+pub async fn merge_diff<F>(key_columns: &[String], hash_options: &pg_hasher::HashOptions, source_rows: Pin<&mut RowStream>,
+                           dest_rows: Pin<&mut RowStream>, emit: F) -> Result<(u64, u64)>
+    where F: FnMut(RowDiff) -> Result<()> {
+    let mut source_decoder = pg_hasher::RowDecoder::new("source", hash_options);
+    let mut dest_decoder = pg_hasher::RowDecoder::new("destination", hash_options);
+    let source = source_rows.map(|row| source_decoder.decode(&row.context(ErrorKind::Query)?).context(ErrorKind::Query));
+    let dest = dest_rows.map(|row| dest_decoder.decode(&row.context(ErrorKind::Query)?).context(ErrorKind::Query));
+    merge_records(key_columns, hash_options, source, dest, emit).await
+}
+
+/// The merge join of merge_diff, over decoded rows. Rows pair up by their normalized keys,
+/// like they do in key_diff, so that --sorted finds the same differences.
+async fn merge_records<S, D, F>(key_columns: &[String], hash_options: &pg_hasher::HashOptions, mut source_rows: S,
+                                mut dest_rows: D, mut emit: F) -> Result<(u64, u64)>
+    where S: Stream<Item = Result<Record>> + Unpin, D: Stream<Item = Result<Record>> + Unpin, F: FnMut(RowDiff) -> Result<()> {
+    let mut source_read: u64 = 0;
+    let mut dest_read: u64 = 0;
+    let mut source = next_sorted_row(&mut source_rows, &None, key_columns, hash_options, "source").await?;
+    let mut dest = next_sorted_row(&mut dest_rows, &None, key_columns, hash_options, "destination").await?;
+    loop {
+        let ord = match (&source, &dest) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((s, _)), Some((d, _))) => pg_hasher::normalized_key_cmp(s, d),
+        };
+        if ord != Ordering::Greater {
+            let next = next_sorted_row(&mut source_rows, &source, key_columns, hash_options, "source").await?;
+            source_read += 1;
+            let (_key, row) = std::mem::replace(&mut source, next).unwrap();
+            if ord == Ordering::Less {
+                emit(RowDiff::Removed { row })?;
+                continue;
+            }
+            let next = next_sorted_row(&mut dest_rows, &dest, key_columns, hash_options, "destination").await?;
+            dest_read += 1;
+            let (_key, dest_row) = std::mem::replace(&mut dest, next).unwrap();
+            // Identical fingerprints are the common case; rows with their columns in another order
+            // fingerprint differently, but only differ when a column does
+            if pg_hasher::row_hasher(&row, hash_options) != pg_hasher::row_hasher(&dest_row, hash_options) {
+                let source_map = pg_hasher::row_map(&row);
+                let dest_map = pg_hasher::row_map(&dest_row);
+                let key = row_key(key_columns, &source_map)?;
+                if let Some(diff) = paired_diff(key, row, &source_map, dest_row, &dest_map, hash_options) {
+                    emit(diff)?;
+                }
+            }
+        } else {
+            let next = next_sorted_row(&mut dest_rows, &dest, key_columns, hash_options, "destination").await?;
+            dest_read += 1;
+            let (_key, row) = std::mem::replace(&mut dest, next).unwrap();
+            emit(RowDiff::Added { row })?;
+        }
+    }
+    Ok((source_read, dest_read))
+}
+
+pub fn key_as_string(key_columns: &[String], key: &[String]) -> String {
+    let mut key_vals: Vec<String> = Vec::new();
+    for (col, val) in key_columns.iter().zip(key.iter()) {
+        key_vals.push(format!("{}: {}", col, val));
+    }
+    format!("[ {} ]", key_vals.join(", "))
+}
+
+pub fn changes_as_string(changes: &[ColumnChange]) -> String {
+    let mut col_changes: Vec<String> = Vec::new();
+    for change in changes {
+        col_changes.push(format!("{}: {} => {}", change.column, change.old, change.new));
+    }
+    col_changes.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_postgres::types::Type;
+    use crate::pg_hasher::Value;
+    use crate::pg_hasher::test_support::{options, record, text};
+
+    #[test]
+    fn columns_in_another_order() {
+        let key = [String::from("id")];
+        let source = record(&[("id", Type::INT4), ("name", Type::TEXT)], vec![Value::Int(1), text("a")]);
+        let same = record(&[("name", Type::TEXT), ("id", Type::INT4)], vec![text("a"), Value::Int(1)]);
+        let changed = record(&[("name", Type::TEXT), ("id", Type::INT4)], vec![text("b"), Value::Int(1)]);
+        assert!(key_diff(&key, &options(), vec![source.clone()], vec![same.clone()]).unwrap().is_empty());
+        let (source_map, same_map) = (pg_hasher::row_map(&source), pg_hasher::row_map(&same));
+        assert!(paired_diff(vec![String::from("1")], source.clone(), &source_map, same, &same_map, &options()).is_none());
+        let diffs = key_diff(&key, &options(), vec![source], vec![changed]).unwrap();
+        match diffs.as_slice() {
+            [RowDiff::Changed { changes, .. }] => {
+                assert_eq!(changes.len(), 1);
+                assert_eq!((changes[0].column.as_str(), changes[0].old.as_str(), changes[0].new.as_str()), ("name", "'a'", "'b'"));
+            },
+            _ => panic!("expected one changed row"),
+        }
+    }
+
+    #[test]
+    fn strict_key_types() {
+        let key = [String::from("id")];
+        let options = pg_hasher::HashOptions { strict_types: true, ..options() };
+        let source = record(&[("id", Type::INT4), ("name", Type::TEXT)], vec![Value::Int(1), text("a")]);
+        let dest = record(&[("id", Type::INT8), ("name", Type::TEXT)], vec![Value::Int(1), text("a")]);
+        let diffs = key_diff(&key, &options, vec![source], vec![dest]).unwrap();
+        match diffs.as_slice() {
+            [RowDiff::Changed { changes, .. }] => {
+                assert_eq!(changes.len(), 1);
+                assert_eq!((changes[0].column.as_str(), changes[0].old.as_str(), changes[0].new.as_str()), ("id", "1::int4", "1::int8"));
+            },
+            _ => panic!("expected one changed row"),
+        }
+    }
+
+    #[test]
+    fn key_order() {
+        let key = [String::from("id")];
+        let columns = [("id", Type::INT4), ("name", Type::TEXT)];
+        let row = |id: i64, name: &str| record(&columns, vec![Value::Int(id), text(name)]);
+        let source = vec![row(200, "a"), row(3, "a"), row(20, "a"), row(20, "b")];
+        let dest = vec![row(20, "a"), row(3, "b"), row(1000, "a")];
+        let diffs = key_diff(&key, &options(), source, dest).unwrap();
+        let keys: Vec<(&str, String)> = diffs.iter().map(|diff| match diff {
+            RowDiff::Added { row } => ("added", row.values[0].to_string()),
+            RowDiff::Removed { row } => ("removed", row.values[0].to_string()),
+            RowDiff::Changed { key, .. } => ("changed", key[0].clone()),
+        }).collect();
+        assert_eq!(keys, vec![
+            ("changed", String::from("3")), ("removed", String::from("20")),
+            ("removed", String::from("200")), ("added", String::from("1000")),
+        ]);
+    }
+
+    #[test]
+    fn unmatched_duplicates() {
+        let columns = [("name", Type::TEXT)];
+        let (a, b) = (record(&columns, vec![text("a")]), record(&columns, vec![text("b")]));
+        let mut rows = UnmatchedRows::new();
+        rows.add_record(1, a.clone(), 2);
+        rows.add_record(1, a.clone(), 1);
+        rows.add_record(2, b.clone(), 1);
+        assert_eq!(rows.len(), 4);
+        let bytes = rows.memory();
+        assert_eq!(bytes, record_bytes(&a) + record_bytes(&b));
+
+        assert!(rows.take(1));
+        assert!(!rows.take(3));
+        assert_eq!((rows.len(), rows.memory()), (3, bytes));
+        assert!(rows.take(2));
+        assert!(!rows.take(2));
+        assert_eq!((rows.len(), rows.memory()), (2, record_bytes(&a)));
+
+        let mut values: Vec<String> = rows.into_rows().iter().map(|row| row.values[0].to_string()).collect();
+        values.sort();
+        assert_eq!(values, vec!["'a'", "'a'"]);
+    }
+
+    #[test]
+    fn unmatched_merge() {
+        let columns = [("name", Type::TEXT)];
+        let row = |name: &str| record(&columns, vec![text(name)]);
+        // Three copies of a in one part of the source, a and two copies of b in the destination
+        let mut source = UnmatchedRows::new();
+        let mut dest = UnmatchedRows::new();
+        dest.add_record(1, row("a"), 1);
+        dest.add_record(2, row("b"), 2);
+        let mut part = UnmatchedRows::new();
+        part.add_record(1, row("a"), 3);
+        part.add_record(2, row("b"), 1);
+        part.add_record(3, row("c"), 2);
+        source.merge(part, &mut dest);
+
+        // One copy of a and b cancel out
+        assert_eq!((source.len(), dest.len()), (4, 1));
+        let mut counted: Vec<(String, usize)> = source.into_counted_rows().into_iter()
+            .map(|(row, count)| (row.values[0].to_string(), count))
+            .collect();
+        counted.sort();
+        assert_eq!(counted, vec![(String::from("'a'"), 2), (String::from("'c'"), 2)]);
+        let values: Vec<String> = dest.into_rows().iter().map(|row| row.values[0].to_string()).collect();
+        assert_eq!(values, vec!["'b'"]);
+    }
+
+    #[test]
+    fn sorted_normalized_keys() {
+        let key = [String::from("id")];
+        let options = pg_hasher::HashOptions { trim_numeric_scale: true, ..options() };
+        let columns = [("id", Type::NUMERIC), ("name", Type::TEXT)];
+        let row = |id: &str, name: &str| record(&columns, vec![Value::Numeric(String::from(id)), text(name)]);
+        let source = vec![row("1.0", "a"), row("2.50", "b"), row("3", "c")];
+        let dest = vec![row("1", "a"), row("2.5", "x"), row("4", "d")];
+        let summary = |diffs: Vec<RowDiff>| -> Vec<(&str, String)> {
+            diffs.iter().map(|diff| match diff {
+                RowDiff::Added { row } => ("added", row.values[0].to_string()),
+                RowDiff::Removed { row } => ("removed", row.values[0].to_string()),
+                RowDiff::Changed { key, .. } => ("changed", key[0].clone()),
+            }).collect()
+        };
+        let expected = vec![
+            ("changed", String::from("2.50")), ("removed", String::from("3")), ("added", String::from("4")),
+        ];
+        assert_eq!(summary(key_diff(&key, &options, source.clone(), dest.clone()).unwrap()), expected);
+
+        let mut diffs: Vec<RowDiff> = Vec::new();
+        let merged = futures::executor::block_on(merge_records(
+            &key, &options, futures::stream::iter(source.into_iter().map(Ok)), futures::stream::iter(dest.into_iter().map(Ok)),
+            |diff| {
+                diffs.push(diff);
+                Ok(())
+            },
+        )).unwrap();
+        assert_eq!(merged, (3, 3));
+        assert_eq!(summary(diffs), expected);
+
+        // Rows that are not ordered by their key
+        let unordered = vec![row("2", "a"), row("1", "a")];
+        let result = futures::executor::block_on(merge_records(
+            &key, &options, futures::stream::iter(unordered.into_iter().map(Ok)), futures::stream::iter(Vec::new()), |_diff| Ok(())));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::compare::Comparison;
+use crate::tables::{TableOutcome, TableResult};
+
+/// The tables (or schemas) are identical
+pub const IDENTICAL: u8 = 0;
+/// Differences were found
+pub const DIFFERENT: u8 = 1;
+/// More than --max-unmatched rows did not match, so the result is incomplete
+pub const TRUNCATED: u8 = 2;
+/// Any other error, like invalid options
+pub const ERROR: u8 = 3;
+pub const CONNECTION_ERROR: u8 = 4;
+pub const QUERY_ERROR: u8 = 5;
+pub const TYPE_ERROR: u8 = 6;
+
+/// The kind of an error, added as context so that it can be told apart for the exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// Connecting to a database failed
+    Connection,
+    /// A query failed, or reading its rows failed
+    Query,
+    /// A column type cannot be compared
+    Type,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Connection => write!(f, "Connection error"),
+            ErrorKind::Query => write!(f, "Query error"),
+            ErrorKind::Type => write!(f, "Type error"),
+        }
+    }
+}
+
+/// The exit code for an error
+pub fn error_code(e: &anyhow::Error) -> u8 {
+    match e.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::Connection) => CONNECTION_ERROR,
+        Some(ErrorKind::Query) => QUERY_ERROR,
+        Some(ErrorKind::Type) => TYPE_ERROR,
+        None => ERROR,
+    }
+}
+
+/// The exit code for a comparison with this many differences
+pub fn differences_code(differences: usize, truncated: bool) -> u8 {
+    if truncated {
+        TRUNCATED
+    } else if differences > 0 {
+        DIFFERENT
+    } else {
+        IDENTICAL
+    }
+}
+
+pub fn comparison_code(comparison: &Comparison) -> u8 {
+    differences_code(comparison.differences.len(), comparison.truncated)
+}
+
+/// The exit code of a multi-table run: the highest code of all tables,
+/// where tables that only exist on one side are differences
+pub fn table_results_code(results: &[TableResult]) -> u8 {
+    results.iter()
+        .map(|result| match &result.outcome {
+            TableOutcome::OnlyOnSource | TableOutcome::OnlyOnDest => DIFFERENT,
+            TableOutcome::Compared { comparison, .. } => comparison_code(comparison),
+            TableOutcome::Failed(e) => error_code(e),
+        })
+        .max()
+        .unwrap_or(IDENTICAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use super::*;
+    use crate::cli;
+    use crate::compare::Differences;
+    use structopt::StructOpt;
+
+    fn table_result(outcome: TableOutcome) -> TableResult {
+        TableResult { table: String::from("t"), args: cli::Params::from_iter(["dbdiff"]), outcome }
+    }
+
+    fn identical() -> TableOutcome {
+        let comparison = Comparison { source_rows: 1, dest_rows: 1, truncated: false, differences: Differences::Keyed(Vec::new()) };
+        TableOutcome::Compared { warnings: Vec::new(), comparison }
+    }
+
+    #[test]
+    fn differences() {
+        assert_eq!(differences_code(0, false), IDENTICAL);
+        assert_eq!(differences_code(3, false), DIFFERENT);
+        assert_eq!(differences_code(0, true), TRUNCATED);
+        assert_eq!(differences_code(3, true), TRUNCATED);
+    }
+
+    #[test]
+    fn errors() {
+        let failing: Result<(), std::io::Error> = Err(std::io::Error::other("failed"));
+        assert_eq!(error_code(&failing.context(ErrorKind::Connection).unwrap_err()), CONNECTION_ERROR);
+        assert_eq!(error_code(&anyhow::anyhow!("failed").context(ErrorKind::Query)), QUERY_ERROR);
+        assert_eq!(error_code(&anyhow::anyhow!("failed").context(ErrorKind::Type)), TYPE_ERROR);
+        assert_eq!(error_code(&anyhow::anyhow!("Invalid output format")), ERROR);
+    }
+
+    #[test]
+    fn table_results() {
+        assert_eq!(table_results_code(&[]), IDENTICAL);
+        assert_eq!(table_results_code(&[table_result(identical())]), IDENTICAL);
+        assert_eq!(table_results_code(&[table_result(identical()), table_result(TableOutcome::OnlyOnDest)]), DIFFERENT);
+        let failed = TableOutcome::Failed(anyhow::anyhow!("failed").context(ErrorKind::Query));
+        assert_eq!(table_results_code(&[table_result(TableOutcome::OnlyOnSource), table_result(failed)]), QUERY_ERROR);
+    }
+}
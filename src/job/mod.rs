@@ -0,0 +1,248 @@
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::cli;
+use crate::connection;
+
+/// The settings of a connection in a job file
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Connection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dsn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sslmode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sslrootcert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sslcert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sslkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+/// The comparison settings of a job file, named like the command line options
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest_table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest_query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_columns: Option<Vec<String>>,
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    pub where_clause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_unmatched: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spill_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sorted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reverse: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_numeric_scale: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_types: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsupported_types: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decode_errors: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum_rows: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest_schema: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tables: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<bool>,
+}
+
+/// A table or query pair to compare, with the settings that may differ per pair
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pair {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest_table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest_query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_columns: Option<Vec<String>>,
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    pub where_clause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<Vec<String>>,
+}
+
+impl Pair {
+    /// The name of the pair in reports: its name, or else its source table, or else its position
+    pub fn name(&self, n: usize) -> String {
+        self.name.clone().or_else(|| self.source_table.clone()).unwrap_or_else(|| format!("comparison {}", n + 1))
+    }
+}
+
+impl Options {
+    /// These options, with the settings of a pair taking precedence
+    pub fn with_pair(&self, pair: &Pair) -> Options {
+        let mut options = self.clone();
+        let pick = |own: &mut Option<_>, other: &Option<_>| if other.is_some() { *own = other.clone() };
+        pick(&mut options.source_table, &pair.source_table);
+        pick(&mut options.dest_table, &pair.dest_table);
+        pick(&mut options.source_query, &pair.source_query);
+        pick(&mut options.dest_query, &pair.dest_query);
+        pick(&mut options.where_clause, &pair.where_clause);
+        let pick_list = |own: &mut Option<Vec<String>>, other: &Option<Vec<String>>| if other.is_some() { *own = other.clone() };
+        pick_list(&mut options.key, &pair.key);
+        pick_list(&mut options.columns, &pair.columns);
+        pick_list(&mut options.exclude_columns, &pair.exclude_columns);
+        pick_list(&mut options.normalize, &pair.normalize);
+        options
+    }
+}
+
+/// A comparison job: the connections, the options and the table or query pairs to compare.
+/// Read from YAML, or from TOML when the file name ends in .toml.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Job {
+    pub source: Connection,
+    pub destination: Connection,
+    pub options: Options,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub compare: Vec<Pair>,
+}
+
+impl Job {
+    pub fn read(path: &str) -> Result<Job> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read job file {}", path))?;
+        let job = if Path::new(path).extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&contents).map_err(anyhow::Error::from)
+        } else {
+            serde_yaml::from_str(&contents).map_err(anyhow::Error::from)
+        };
+        job.with_context(|| format!("Invalid job file {}", path))
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// The job that the resolved settings describe, with the passwords in connection strings masked
+    pub fn from_params(args: &cli::Params, comparisons: &[(String, cli::Params)]) -> Job {
+        let some = |val: &String| if val.is_empty() { None } else { Some(val.clone()) };
+        let source = Connection {
+            dsn: Some(connection::masked_dsn(&args.source_dsn)),
+            sslmode: some(&args.source_sslmode),
+            sslrootcert: some(&args.source_sslrootcert),
+            sslcert: some(&args.source_sslcert),
+            sslkey: some(&args.source_sslkey),
+            password_file: some(&args.source_password_file),
+            password_command: some(&args.source_password_command),
+            snapshot: some(&args.source_snapshot),
+        };
+        let destination = Connection {
+            dsn: Some(connection::masked_dsn(&args.dest_dsn)),
+            sslmode: some(&args.dest_sslmode),
+            sslrootcert: some(&args.dest_sslrootcert),
+            sslcert: some(&args.dest_sslcert),
+            sslkey: some(&args.dest_sslkey),
+            password_file: some(&args.dest_password_file),
+            password_command: some(&args.dest_password_command),
+            snapshot: some(&args.dest_snapshot),
+        };
+        let mut options = Options {
+            source_table: Some(args.source_table_name.clone()),
+            dest_table: Some(args.dest_table_name.clone()),
+            source_query: Some(args.source_query.clone()),
+            dest_query: Some(args.dest_query.clone()),
+            key: Some(args.key_columns.clone()),
+            columns: Some(args.columns.clone()),
+            exclude_columns: Some(args.exclude_columns.clone()),
+            where_clause: Some(args.where_clause.clone()),
+            normalize: Some(args.normalize.clone()),
+            format: Some(args.output_format.clone()),
+            max_unmatched: Some(args.max_unmatched),
+            spill_dir: Some(args.spill_dir.clone()),
+            max_memory: Some(args.max_memory),
+            sorted: Some(args.sorted),
+            reverse: Some(args.reverse),
+            fingerprint: Some(args.fingerprint.clone()),
+            trim_numeric_scale: Some(args.trim_numeric_scale),
+            strict_types: Some(args.strict_types),
+            unsupported_types: Some(args.unsupported_types.clone()),
+            decode_errors: Some(args.decode_errors.clone()),
+            checksum: Some(args.checksum),
+            checksum_rows: Some(args.checksum_rows),
+            schema: Some(args.schema.clone()),
+            dest_schema: Some(args.dest_schema.clone()),
+            tables: Some(args.tables.clone()),
+            parallel: Some(args.parallel),
+            chunks: Some(args.chunks),
+            chunk_by: Some(args.chunk_by.clone()),
+            snapshot: Some(args.snapshot),
+        };
+        let compare: Vec<Pair> = comparisons.iter()
+            .map(|(name, pair_args)| Pair {
+                name: Some(name.clone()),
+                source_table: Some(pair_args.source_table_name.clone()),
+                dest_table: Some(pair_args.dest_table_name.clone()),
+                source_query: Some(pair_args.source_query.clone()),
+                dest_query: Some(pair_args.dest_query.clone()),
+                key: Some(pair_args.key_columns.clone()),
+                columns: Some(pair_args.columns.clone()),
+                exclude_columns: Some(pair_args.exclude_columns.clone()),
+                where_clause: Some(pair_args.where_clause.clone()),
+                normalize: Some(pair_args.normalize.clone()),
+            })
+            .collect();
+        if !compare.is_empty() {
+            // The settings of the pairs are part of the pairs
+            options = Options { source_table: None, dest_table: None, source_query: None, dest_query: None,
+                                key: None, columns: None, exclude_columns: None, where_clause: None, normalize: None,
+                                ..options };
+        }
+        Job { source, destination, options, compare }
+    }
+}
@@ -1,149 +1,185 @@
-use tokio_postgres::{NoTls, Row, RowStream};
-use futures::{pin_mut, TryStreamExt};
-use futures;
-use core::pin::Pin;
-use std::borrow::Borrow;
-use anyhow::Result;
-use std::collections::HashMap;
+use std::process::ExitCode;
+use futures::pin_mut;
+use anyhow::{Context, Result};
 
+mod checksum;
+mod chunks;
 mod cli;
+mod compare;
+mod connection;
+mod differ;
+mod exit;
+mod job;
+mod output;
 mod pg_hasher;
+mod query;
+mod schema;
+mod spill;
+mod tables;
 
-async fn next_hash(mut rows: Pin<&mut RowStream>, first: bool) -> Result<(Row, u64)> {
-    match rows.try_next().await {
-        Ok(or) => {
-            match or {
-                Some(r) => {
-                    let hash =  pg_hasher::row_hasher(r.borrow(), first);
-                    Ok((r, hash))
-                },
-                None => Err(anyhow::Error::msg("We reached the end of the RowStream")),
-            }
-        },
-        Err(e) => Err(anyhow::Error::from(e)),
+/// Run the comparison, and return the exit code (see exit)
+async fn run() -> Result<u8> {
+    let (mut args, mut comparisons) = cli::Params::get_args()?;
+    if args.print_config {
+        print!("{}", job::Job::from_params(&args, &comparisons).to_yaml()?);
+        return Ok(exit::IDENTICAL);
     }
-}
-
-
-#[tokio::main] // By default, tokio_postgres uses the tokio crate as its runtime.
-async fn main() -> Result<()> {
-    let args = cli::Params::get_args();
-    // Connect to the database.
-    let (source, source_connection) =
-        tokio_postgres::connect(&*args.source_dsn, NoTls).await?;
-
-    // The source_connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move {
-        if let Err(e) = source_connection.await {
-            eprintln!("source connection error: {}", e);
+    connection::resolve_passwords(&mut args, &mut comparisons)?;
+    if let Some(cli::Command::Schema { alter }) = args.command {
+        let source_schema = if args.schema.is_empty() { "public" } else { args.schema.as_str() };
+        let dest_schema = if args.dest_schema.is_empty() { source_schema } else { args.dest_schema.as_str() };
+        let (source, dest) = connection::connect_pair(&args).await?;
+        let (diffs, statements) = schema::compare_schemas(&source, &dest, source_schema, dest_schema).await?;
+        let code = exit::differences_code(diffs.len(), false);
+        output::print_schema_diff(diffs, statements, alter);
+        return Ok(code);
+    }
+    if !output::FORMATS.contains(&args.output_format.as_str()) {
+        return Err(anyhow::anyhow!("Invalid output format {}", args.output_format));
+    }
+    // Tables of multi-table runs and job pairs without key columns use their primary key
+    let multi = args.multi_table() || !comparisons.is_empty();
+    if args.output_format == "sync" && args.key_columns.is_empty() && !multi {
+        return Err(anyhow::anyhow!("The sync output format requires key columns (--key)"));
+    }
+    if args.sorted && args.key_columns.is_empty() && !multi {
+        return Err(anyhow::anyhow!("Sorted (merge join) comparison requires key columns (--key)"));
+    }
+    let hash_options = args.hash_options()?;
+    for (name, pair_args) in &comparisons {
+        pair_args.hash_options().with_context(|| format!("In comparison {}", name))?;
+    }
+    let unsupported_types: query::UnsupportedTypes = args.unsupported_types.parse()?;
+    // The connections that export the snapshots stay open until the comparison is done
+    let _snapshot_holders = if args.snapshot_reads() {
+        let (source, dest) = connection::connect_pair(&args).await?;
+        for snapshot in connection::share_snapshots(&mut args, &source, &dest).await? {
+            output::print_snapshot(&args, &snapshot);
         }
-    });
-
-    // We need to pass in params, and we need to define params for async operations
-    // Lets define as array of 32 bit integer with 0 elements
-    let params:&[i32] = &[];
-    // And run the query on the source connection
-    let source_rows = source.query_raw(&args.source_query, params).await?;
-
-    let (dest, dest_connection) =
-        tokio_postgres::connect(&*args.source_dsn, NoTls).await?;
-
-    // The dest_connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move {
-        if let Err(e) = dest_connection.await {
-            eprintln!("dest connection error: {}", e);
+        for (_name, pair_args) in comparisons.iter_mut() {
+            pair_args.source_snapshot = args.source_snapshot.clone();
+            pair_args.dest_snapshot = args.dest_snapshot.clone();
+            pair_args.source_snapshot_lsn = args.source_snapshot_lsn.clone();
+            pair_args.dest_snapshot_lsn = args.dest_snapshot_lsn.clone();
         }
-    });
-
-    // And run the query on the dest connection
-    let dest_rows = dest
-        .query_raw(&args.dest_query, params).await?;
+        Some((source, dest))
+    } else {
+        None
+    };
+    if !comparisons.is_empty() {
+        let results = tables::compare_pairs(&args, comparisons, unsupported_types).await?;
+        let code = exit::table_results_code(&results);
+        output::print_table_results(&args, results)?;
+        return Ok(code);
+    }
+    if args.multi_table() {
+        let results = tables::compare_tables(&args, unsupported_types).await?;
+        let code = exit::table_results_code(&results);
+        output::print_table_results(&args, results)?;
+        return Ok(code);
+    }
 
-    // And then check that we got back the same string we sent over.
+    // Connect to the databases, and run the queries
+    let (source, dest) = connection::connect_pair(&args).await?;
+    if args.checksum || args.chunks > 1 {
+        let (warnings, comparison) = if args.checksum {
+            checksum::compare_checksummed(&source, &dest, &args, &hash_options, unsupported_types).await?
+        } else {
+            chunks::compare_chunked(&source, &dest, &args, &hash_options, unsupported_types).await?
+        };
+        for warning in warnings {
+            output::print_warning(&args, &warning);
+        }
+        let code = exit::comparison_code(&comparison);
+        output::print_comparison(&args, comparison)?;
+        return Ok(code);
+    }
+    let (source_rows, dest_rows, warnings) = compare::query_streams(&source, &dest, &args, &hash_options, unsupported_types).await?;
+    for warning in warnings {
+        output::print_warning(&args, &warning);
+    }
 
     pin_mut!(source_rows);
-    let mut source_done: bool = false;
     pin_mut!(dest_rows);
-    let mut dest_done: bool = false;
-    let mut i: u32 = 0;
-    let mut _of: bool = false;
-    let mut source_distinct_rows: HashMap<u64, Row> = HashMap::new();
-    let mut dest_distinct_rows: HashMap<u64, Row> = HashMap::new();
-    loop {
-        if source_done && dest_done {
-            break
-        } else if dest_distinct_rows.len() + source_distinct_rows.len() > args.max_unmatched {
-            break
-        }
-        if i%2 == 0 {
-            if source_done {
-                // Add one, don't care about overflow
-                (i, _of) = i.overflowing_add(1);
-            } else {
-                match next_hash(source_rows.as_mut(), false).await {
-                    Ok((r, h)) => {
-                        if dest_distinct_rows.contains_key(&h) {
-                            dest_distinct_rows.remove(&h);
-                        } else {
-                            source_distinct_rows.insert(h, r);
-                            (i, _of) = i.overflowing_add(1);
-                        }
-                    }
-                    Err(e) => if e.to_string() == "We reached the end of the RowStream" {
-                        source_done = true
-                    } else {
-                        return Err(e)
-                    }
-                }
-            }
-
-        } else {
-            if dest_done {
-                // Add one, don't care about overflow
-                (i, _of) = i.overflowing_add(1);
-            } else {
-                match next_hash(dest_rows.as_mut(), false).await{
-                    Ok((r, h)) => {
-                        if source_distinct_rows.contains_key(&h) {
-                            source_distinct_rows.remove(&h);
-                        } else {
-                            dest_distinct_rows.insert(h, r);
-                            (i, _of) = i.overflowing_add(1);
-                        }
-                    }
-                    Err(e) => if e.to_string() == "We reached the end of the RowStream" {
-                        dest_done = true
-                    } else {
-                        return Err(e)
-                    }
+    if args.sorted && args.output_format == "json" {
+        // A JSON document can only be printed when all differences are known
+        let mut diffs: Vec<differ::RowDiff> = Vec::new();
+        let (source_read, dest_read) = differ::merge_diff(
+            &args.key_columns, &hash_options, source_rows.as_mut(), dest_rows.as_mut(),
+            |diff| {
+                diffs.push(diff);
+                Ok(())
+            },
+        ).await?;
+        let comparison = compare::Comparison {
+            source_rows: source_read,
+            dest_rows: dest_read,
+            truncated: false,
+            differences: compare::Differences::Keyed(diffs),
+        };
+        let code = exit::comparison_code(&comparison);
+        output::print_comparison(&args, comparison)?;
+        return Ok(code);
+    }
+    if args.sorted {
+        let mut script = if args.output_format == "sync" { Some(output::SyncScript::begin(&args)?) } else { None };
+        let mut counts = output::DiffCounts::default();
+        let (source_read, dest_read) = differ::merge_diff(
+            &args.key_columns, &hash_options, source_rows.as_mut(), dest_rows.as_mut(),
+            |diff| {
+                counts.add(&diff);
+                match script.as_mut() {
+                    Some(script) => script.add(&args, diff),
+                    None => output::print_row_diff(&args, diff),
                 }
-            }
+            },
+        ).await?;
+        if let Some(script) = script {
+            script.commit()?;
         }
+        output::print_summary(&args, source_read, dest_read, &counts);
+        return Ok(exit::differences_code(counts.added + counts.removed + counts.changed, false));
     }
-    println!("Processed: {}", i+1);
-    match args.output_format.as_str() {
-        "hashmap" => {
-            for (_h, r) in source_distinct_rows {
-                println!("< {}", pg_hasher::row_as_string(r.borrow(), false));
-            }
-            for (_h, r) in dest_distinct_rows {
-                println!("> {}", pg_hasher::row_as_string(r.borrow(), false));
-            }
-        },
-        "insert" => {
-            for (_h, r) in source_distinct_rows {
-                println!("< {}", pg_hasher::row_as_insert(args.dest_table_name.as_str(), r.borrow(), false));
-            }
-            for (_h, r) in dest_distinct_rows {
-                println!("> {}", pg_hasher::row_as_insert(args.source_table_name.as_str(), r.borrow(), false));
+
+    let unmatched = compare::hash_unmatched(source_rows.as_mut(), dest_rows.as_mut(), &args, &hash_options, None).await?;
+    if unmatched.spilled.is_some() && args.output_format != "json" {
+        // Print the differences as the spilled rows are paired, instead of reading them all back in memory
+        let mut script = if args.output_format == "sync" { Some(output::SyncScript::begin(&args)?) } else { None };
+        let mut counts = output::DiffCounts::default();
+        let (source_read, dest_read) = (unmatched.source_rows, unmatched.dest_rows);
+        compare::stream_differences(&args, &hash_options, unmatched, |difference| {
+            counts.add_difference(&difference);
+            match (script.as_mut(), difference) {
+                (Some(script), compare::Difference::Keyed(diff)) => script.add(&args, diff),
+                (_, difference) => output::print_difference(&args, difference),
             }
-        },
-        _ => {
-            return Err(anyhow::anyhow!("Invalid output format {}", args.output_format));
+        })?;
+        if let Some(script) = script {
+            script.commit()?;
         }
+        output::print_summary(&args, source_read, dest_read, &counts);
+        return Ok(exit::differences_code(counts.added + counts.removed + counts.changed, false));
     }
+    let (source_read, dest_read, truncated) = (unmatched.source_rows, unmatched.dest_rows, unmatched.truncated);
+    let (source_unmatched, dest_unmatched) = unmatched.into_rows()?;
+    let comparison = compare::Comparison {
+        source_rows: source_read,
+        dest_rows: dest_read,
+        truncated,
+        differences: compare::differences(&args, &hash_options, source_unmatched, dest_unmatched)?,
+    };
+    let code = exit::comparison_code(&comparison);
+    output::print_comparison(&args, comparison)?;
+    Ok(code)
+}
 
-    Ok(())
+#[tokio::main] // By default, tokio_postgres uses the tokio crate as its runtime.
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit::error_code(&e))
+        },
+    }
 }
@@ -0,0 +1,510 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use crate::cli;
+use crate::compare::{Comparison, Difference, Differences};
+use crate::connection::Snapshot;
+use crate::differ::RowDiff;
+use crate::pg_hasher;
+use crate::pg_hasher::Record;
+use crate::schema::SchemaDiff;
+use crate::tables::{TableOutcome, TableResult};
+
+pub const FORMATS: [&str; 5] = ["hashmap", "insert", "sync", "json", "ndjson"];
+
+fn json_format(args: &cli::Params) -> bool {
+    args.output_format == "json" || args.output_format == "ndjson"
+}
+
+/// Print an informational line, as a comment for the sync format to keep the script runnable,
+/// and on stderr for the json formats to keep the output parsable
+fn print_info(args: &cli::Params, info: &str) {
+    if args.output_format == "sync" {
+        println!("-- {}", info);
+    } else if json_format(args) {
+        eprintln!("{}", info);
+    } else {
+        println!("{}", info);
+    }
+}
+
+/// The number of differences of each kind
+#[derive(Default)]
+pub struct DiffCounts {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+impl DiffCounts {
+    pub fn add(&mut self, diff: &RowDiff) {
+        match diff {
+            RowDiff::Added { .. } => self.added += 1,
+            RowDiff::Removed { .. } => self.removed += 1,
+            RowDiff::Changed { .. } => self.changed += 1,
+        }
+    }
+
+    pub fn add_difference(&mut self, difference: &Difference) {
+        match difference {
+            Difference::Keyed(diff) => self.add(diff),
+            Difference::Unkeyed { is_source: true, copies, .. } => self.removed += copies,
+            Difference::Unkeyed { is_source: false, copies, .. } => self.added += copies,
+        }
+    }
+
+    fn of(differences: &Differences) -> DiffCounts {
+        let mut counts = DiffCounts::default();
+        match differences {
+            Differences::Keyed(diffs) => diffs.iter().for_each(|diff| counts.add(diff)),
+            Differences::Unkeyed { source, dest } => {
+                counts.removed = source.iter().map(|(_row, copies)| copies).sum();
+                counts.added = dest.iter().map(|(_row, copies)| copies).sum();
+            },
+        }
+        counts
+    }
+}
+
+/// The snapshot of a side and the WAL position it was exported at, null when it was passed in
+fn snapshot_as_json(id: &str, lsn: &str) -> serde_json::Value {
+    let lsn = if lsn.is_empty() { serde_json::Value::Null } else { serde_json::Value::from(lsn) };
+    serde_json::json!({ "id": id, "lsn": lsn })
+}
+
+fn summary_as_json(args: &cli::Params, source_rows: u64, dest_rows: u64, truncated: bool, counts: &DiffCounts) -> serde_json::Value {
+    let mut summary = serde_json::json!({
+        "processed": source_rows + dest_rows,
+        "source_rows": source_rows,
+        "destination_rows": dest_rows,
+        "truncated": truncated,
+        "differences": counts.added + counts.removed + counts.changed,
+        "added": counts.added,
+        "removed": counts.removed,
+        "changed": counts.changed,
+    });
+    if args.snapshot_reads() {
+        summary["snapshots"] = serde_json::json!({
+            "source": snapshot_as_json(&args.source_snapshot, &args.source_snapshot_lsn),
+            "destination": snapshot_as_json(&args.dest_snapshot, &args.dest_snapshot_lsn),
+        });
+    }
+    summary
+}
+
+/// The key columns of a row, with typed values
+fn key_as_json(args: &cli::Params, row: &Record) -> serde_json::Value {
+    let mut values = pg_hasher::row_as_json(row);
+    let mut key = serde_json::Map::new();
+    for col in &args.key_columns {
+        key.insert(col.clone(), values.remove(col).unwrap_or(serde_json::Value::Null));
+    }
+    serde_json::Value::Object(key)
+}
+
+/// A difference as an object with its kind, the side(s) it is on, the key and the column values
+fn diff_as_json(args: &cli::Params, diff: &RowDiff) -> serde_json::Value {
+    match diff {
+        RowDiff::Removed { row } => serde_json::json!({
+            "kind": "removed", "side": "source",
+            "key": key_as_json(args, row), "row": pg_hasher::row_as_json(row),
+        }),
+        RowDiff::Added { row } => serde_json::json!({
+            "kind": "added", "side": "destination",
+            "key": key_as_json(args, row), "row": pg_hasher::row_as_json(row),
+        }),
+        RowDiff::Changed { source, dest, changes, .. } => {
+            let source_values = pg_hasher::row_as_json(source);
+            let dest_values = pg_hasher::row_as_json(dest);
+            let changes: Vec<serde_json::Value> = changes.iter()
+                .map(|c| serde_json::json!({
+                    "column": c.column,
+                    "source": source_values.get(&c.column),
+                    "destination": dest_values.get(&c.column),
+                }))
+                .collect();
+            serde_json::json!({
+                "kind": "changed", "side": "both", "key": key_as_json(args, source),
+                "source": source_values, "destination": dest_values, "changes": changes,
+            })
+        },
+    }
+}
+
+/// An unmatched row of a comparison without key columns, with its number of copies
+fn counted_row_as_json(kind: &str, side: &str, row: &Record, copies: usize) -> serde_json::Value {
+    serde_json::json!({ "kind": kind, "side": side, "row": pg_hasher::row_as_json(row), "copies": copies })
+}
+
+/// The differences and the summary of a comparison as JSON values
+fn comparison_as_json(args: &cli::Params, comparison: Comparison) -> (Vec<serde_json::Value>, serde_json::Value) {
+    let summary = summary_as_json(args, comparison.source_rows, comparison.dest_rows, comparison.truncated,
+                                  &DiffCounts::of(&comparison.differences));
+    let differences = match comparison.differences {
+        Differences::Keyed(diffs) => diffs.iter().map(|diff| diff_as_json(args, diff)).collect(),
+        Differences::Unkeyed { source, dest } => source.iter()
+            .map(|(row, copies)| counted_row_as_json("removed", "source", row, *copies))
+            .chain(dest.iter().map(|(row, copies)| counted_row_as_json("added", "destination", row, *copies)))
+            .collect(),
+    };
+    (differences, summary)
+}
+
+/// Print a comparison as one JSON document, or (ndjson) as one object per line with a summary object last
+fn print_json_comparison(args: &cli::Params, comparison: Comparison) {
+    let (differences, summary) = comparison_as_json(args, comparison);
+    if args.output_format == "json" {
+        println!("{}", serde_json::json!({ "differences": differences, "summary": summary }));
+        return;
+    }
+    for diff in differences {
+        println!("{}", diff);
+    }
+    print_ndjson_summary(summary);
+}
+
+fn print_ndjson_summary(mut summary: serde_json::Value) {
+    summary["kind"] = serde_json::Value::from("summary");
+    println!("{}", summary);
+}
+
+/// Print the end of a streamed comparison: the number of processed rows, or the summary object for ndjson
+pub fn print_summary(args: &cli::Params, source_rows: u64, dest_rows: u64, counts: &DiffCounts) {
+    if args.output_format == "ndjson" {
+        print_ndjson_summary(summary_as_json(args, source_rows, dest_rows, false, counts));
+    } else {
+        print_processed(args, source_rows, dest_rows);
+    }
+}
+
+pub fn print_processed(args: &cli::Params, source_rows: u64, dest_rows: u64) {
+    print_info(args, &format!("Processed: {} (source: {}, destination: {})",
+                              source_rows + dest_rows, source_rows, dest_rows));
+}
+
+/// Warn that a comparison stopped early, so that a partial result is not mistaken for a complete one
+fn print_truncated(args: &cli::Params) {
+    print_info(args, &format!(
+        "Truncated: the comparison stopped when more than {} rows did not match, so not all rows were read \
+         and the differences are incomplete. Raise --max-unmatched, or use --spill-dir to compare all rows.",
+        args.max_unmatched));
+}
+
+pub fn print_snapshot(args: &cli::Params, snapshot: &Snapshot) {
+    if snapshot.lsn.is_empty() {
+        print_info(args, &format!("Snapshot {}: {}", snapshot.side, snapshot.id));
+    } else {
+        print_info(args, &format!("Snapshot {}: {} at {}", snapshot.side, snapshot.id, snapshot.lsn));
+    }
+}
+
+pub fn print_warning(args: &cli::Params, warning: &str) {
+    print_info(args, &format!("Warning: {}", warning));
+}
+
+fn copies_as_string(copies: usize) -> String {
+    if copies > 1 {
+        format!(" ({} copies)", copies)
+    } else {
+        String::new()
+    }
+}
+
+/// Print the unmatched rows of a comparison without key columns.
+/// Every row comes with the number of copies that could not be matched on the other side.
+pub fn print_distinct_rows(args: &cli::Params, source_rows: Vec<(Record, usize)>, dest_rows: Vec<(Record, usize)>) -> Result<()> {
+    for (r, copies) in source_rows {
+        print_counted_row(args, true, &r, copies)?;
+    }
+    for (r, copies) in dest_rows {
+        print_counted_row(args, false, &r, copies)?;
+    }
+    Ok(())
+}
+
+/// Print an unmatched row of a comparison without key columns, see print_distinct_rows
+fn print_counted_row(args: &cli::Params, is_source: bool, r: &Record, copies: usize) -> Result<()> {
+    let (marker, kind, side, table_name) = if is_source {
+        ("<", "removed", "source", args.dest_table_name.as_str())
+    } else {
+        (">", "added", "destination", args.source_table_name.as_str())
+    };
+    match args.output_format.as_str() {
+        "hashmap" => println!("{} {}{}", marker, pg_hasher::row_as_string(r), copies_as_string(copies)),
+        "insert" => {
+            let insert = pg_hasher::row_as_insert(table_name, r);
+            for _ in 0..copies {
+                println!("{} {}", marker, insert);
+            }
+        },
+        "ndjson" => println!("{}", counted_row_as_json(kind, side, r, copies)),
+        _ => {
+            return Err(anyhow::anyhow!("Invalid output format {}", args.output_format));
+        }
+    }
+    Ok(())
+}
+
+/// Print a difference as it is found, see print_row_diff and print_counted_row
+pub fn print_difference(args: &cli::Params, difference: Difference) -> Result<()> {
+    match difference {
+        Difference::Keyed(diff) => print_row_diff(args, diff),
+        Difference::Unkeyed { is_source, row, copies } => print_counted_row(args, is_source, &row, copies),
+    }
+}
+
+/// Print a single difference of a comparison with key columns.
+/// For the sync format this only prints the statement, see print_key_diff for the full script.
+pub fn print_row_diff(args: &cli::Params, diff: RowDiff) -> Result<()> {
+    match args.output_format.as_str() {
+        "hashmap" => {
+            match diff {
+                RowDiff::Removed { row } =>
+                    println!("< {}", pg_hasher::row_as_string(&row)),
+                RowDiff::Added { row } =>
+                    println!("> {}", pg_hasher::row_as_string(&row)),
+                RowDiff::Changed { key, changes, .. } =>
+                    println!("~ {} {}", crate::differ::key_as_string(&args.key_columns, &key),
+                             crate::differ::changes_as_string(&changes)),
+            }
+        },
+        "insert" => {
+            match diff {
+                RowDiff::Removed { row } =>
+                    println!("< {}", pg_hasher::row_as_insert(args.dest_table_name.as_str(), &row)),
+                RowDiff::Added { row } =>
+                    println!("> {}", pg_hasher::row_as_insert(args.source_table_name.as_str(), &row)),
+                RowDiff::Changed { source, dest, .. } => {
+                    println!("< {}", pg_hasher::row_as_insert(args.dest_table_name.as_str(), &source));
+                    println!("> {}", pg_hasher::row_as_insert(args.source_table_name.as_str(), &dest));
+                },
+            }
+        },
+        "sync" => println!("{}", sync_statement(args, diff).1),
+        "ndjson" => println!("{}", diff_as_json(args, &diff)),
+        _ => {
+            return Err(anyhow::anyhow!("Invalid output format {}", args.output_format));
+        }
+    }
+    Ok(())
+}
+
+/// Print all differences of a comparison with key columns
+pub fn print_key_diff(args: &cli::Params, diffs: Vec<RowDiff>) -> Result<()> {
+    if args.output_format == "sync" {
+        print_sync_script(args, diffs);
+        return Ok(());
+    }
+    for diff in diffs {
+        print_row_diff(args, diff)?;
+    }
+    Ok(())
+}
+
+/// The statement that resolves a difference, when making the destination equal to the source
+/// (or the other way around with --reverse).
+/// It comes with its phase in the script: 0 for deletes, 1 for updates and 2 for inserts.
+fn sync_statement(args: &cli::Params, diff: RowDiff) -> (u8, String) {
+    let table_name = if args.reverse { args.source_table_name.as_str() } else { args.dest_table_name.as_str() };
+    match (diff, args.reverse) {
+        (RowDiff::Removed { row }, false) | (RowDiff::Added { row }, true) =>
+            (2, pg_hasher::row_as_insert(table_name, &row)),
+        (RowDiff::Added { row }, false) | (RowDiff::Removed { row }, true) =>
+            (0, pg_hasher::row_as_delete(table_name, &args.key_columns, &row)),
+        (RowDiff::Changed { source, dest, changes, .. }, reverse) => {
+            let row = if reverse { dest } else { source };
+            let columns: Vec<&str> = changes.iter().map(|c| c.column.as_str()).collect();
+            (1, pg_hasher::row_as_update(table_name, &args.key_columns, &row, &columns))
+        },
+    }
+}
+
+/// Print a transactional script that resolves all differences.
+/// Deletes go first, so that inserts don't conflict with rows that are about to be removed.
+fn print_sync_script(args: &cli::Params, diffs: Vec<RowDiff>) {
+    let mut statements: Vec<(u8, String)> = diffs.into_iter()
+        .map(|diff| sync_statement(args, diff))
+        .collect();
+    statements.sort_by_key(|(phase, _statement)| *phase);
+    println!("begin;");
+    for (_phase, statement) in statements {
+        println!("{}", statement);
+    }
+    println!("commit;");
+}
+
+/// A sync script for differences that come one at a time (--sorted). Deletes are printed right away,
+/// updates and inserts go to temporary files that are printed after them, so that the script has the same
+/// order as print_sync_script. The files are in the spill directory, and removed when dropped.
+pub struct SyncScript {
+    later: Vec<(PathBuf, BufWriter<File>)>,
+}
+
+impl SyncScript {
+    pub fn begin(args: &cli::Params) -> Result<SyncScript> {
+        let dir = args.spill_path().unwrap_or_else(std::env::temp_dir);
+        let mut later = Vec::new();
+        for phase in ["updates", "inserts"] {
+            let path = dir.join(format!("dbdiff-{}-{}.sql", std::process::id(), phase));
+            let file = File::create(&path)
+                .with_context(|| format!("Could not create temporary file {}", path.display()))?;
+            later.push((path, BufWriter::new(file)));
+        }
+        println!("begin;");
+        Ok(SyncScript { later })
+    }
+
+    pub fn add(&mut self, args: &cli::Params, diff: RowDiff) -> Result<()> {
+        match sync_statement(args, diff) {
+            (0, statement) => println!("{}", statement),
+            (phase, statement) => {
+                let (path, writer) = &mut self.later[phase as usize - 1];
+                writeln!(writer, "{}", statement)
+                    .with_context(|| format!("Could not write temporary file {}", path.display()))?;
+            },
+        }
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        for (path, writer) in self.later.iter_mut() {
+            writer.flush().with_context(|| format!("Could not write temporary file {}", path.display()))?;
+            let mut file = File::open(&path)
+                .with_context(|| format!("Could not read temporary file {}", path.display()))?;
+            std::io::copy(&mut file, &mut out)
+                .with_context(|| format!("Could not read temporary file {}", path.display()))?;
+        }
+        writeln!(out, "commit;")?;
+        Ok(())
+    }
+}
+
+impl Drop for SyncScript {
+    fn drop(&mut self) {
+        for (path, _writer) in &self.later {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub fn print_comparison(args: &cli::Params, comparison: Comparison) -> Result<()> {
+    if comparison.truncated {
+        print_truncated(args);
+    }
+    if json_format(args) {
+        print_json_comparison(args, comparison);
+        return Ok(());
+    }
+    print_processed(args, comparison.source_rows, comparison.dest_rows);
+    match comparison.differences {
+        Differences::Keyed(diffs) => print_key_diff(args, diffs),
+        Differences::Unkeyed { source, dest } => print_distinct_rows(args, source, dest),
+    }
+}
+
+/// Print the report of a multi-table run as JSON: an object per table with its status, differences and summary.
+/// For ndjson every difference has the table, and a summary object follows the differences of each table.
+fn print_json_table_results(args: &cli::Params, results: Vec<TableResult>) {
+    let mut tables: Vec<serde_json::Value> = Vec::new();
+    for result in results {
+        let mut table = serde_json::json!({ "table": result.table });
+        let mut differences: Vec<serde_json::Value> = Vec::new();
+        match result.outcome {
+            TableOutcome::OnlyOnSource => table["status"] = serde_json::Value::from("only_on_source"),
+            TableOutcome::OnlyOnDest => table["status"] = serde_json::Value::from("only_on_destination"),
+            TableOutcome::Failed(e) => {
+                table["status"] = serde_json::Value::from("failed");
+                table["error"] = serde_json::Value::from(e.to_string());
+            },
+            TableOutcome::Compared { warnings, comparison } => {
+                for warning in warnings {
+                    print_warning(&result.args, &format!("{}: {}", result.table, warning));
+                }
+                // A truncated comparison did not read all rows, so it is neither identical nor complete
+                let status = match (comparison.truncated, comparison.differences.len()) {
+                    (true, _) => "truncated",
+                    (false, 0) => "identical",
+                    (false, _) => "different",
+                };
+                let (diffs, summary) = comparison_as_json(&result.args, comparison);
+                table["status"] = serde_json::Value::from(status);
+                table["summary"] = summary;
+                differences = diffs;
+            },
+        }
+        if args.output_format == "json" {
+            table["differences"] = serde_json::Value::from(differences);
+            tables.push(table);
+            continue;
+        }
+        for mut diff in differences {
+            diff["table"] = table["table"].clone();
+            println!("{}", diff);
+        }
+        table["kind"] = serde_json::Value::from("summary");
+        println!("{}", table);
+    }
+    if args.output_format == "json" {
+        println!("{}", serde_json::json!({ "tables": tables }));
+    }
+}
+
+/// Print the report of a multi-table run: the differences per table, followed by a summary
+pub fn print_table_results(args: &cli::Params, results: Vec<TableResult>) -> Result<()> {
+    if json_format(args) {
+        print_json_table_results(args, results);
+        return Ok(());
+    }
+    let mut summary: Vec<String> = Vec::new();
+    for result in results {
+        let status = match result.outcome {
+            TableOutcome::OnlyOnSource => String::from("only exists on the source"),
+            TableOutcome::OnlyOnDest => String::from("only exists on the destination"),
+            TableOutcome::Failed(e) => format!("failed: {}", e),
+            TableOutcome::Compared { warnings, comparison } => {
+                print_info(&result.args, &format!("=== {}", result.table));
+                for warning in warnings {
+                    print_warning(&result.args, &warning);
+                }
+                let differences = comparison.differences.len();
+                let truncated = if comparison.truncated { " (truncated)" } else { "" };
+                print_comparison(&result.args, comparison)?;
+                match differences {
+                    0 => format!("identical{}", truncated),
+                    1 => format!("1 difference{}", truncated),
+                    n => format!("{} differences{}", n, truncated),
+                }
+            },
+        };
+        summary.push(format!("{}: {}", result.table, status));
+    }
+    print_info(args, "Summary:");
+    for line in summary {
+        print_info(args, &line);
+    }
+    Ok(())
+}
+
+/// Print the differences between two schemas, and with alter the statements that resolve them
+pub fn print_schema_diff(diffs: Vec<SchemaDiff>, statements: Vec<String>, alter: bool) {
+    // With alter, the differences are comments in the script (and view definitions span multiple lines)
+    let prefix = if alter { "-- " } else { "" };
+    for diff in diffs {
+        let line = match diff {
+            SchemaDiff::OnlyOnSource(name, def) => format!("< {}: {}", name, def),
+            SchemaDiff::OnlyOnDest(name, def) => format!("> {}: {}", name, def),
+            SchemaDiff::Changed(name, source, dest) => format!("~ {}: {} => {}", name, source, dest),
+        };
+        println!("{}{}", prefix, line.replace('\n', &format!("\n{}", prefix)));
+    }
+    if alter {
+        println!("begin;");
+        for statement in statements {
+            println!("{}", statement);
+        }
+        println!("commit;");
+    }
+}
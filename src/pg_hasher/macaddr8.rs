@@ -0,0 +1,27 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+/// A MACADDR8 (EUI-64) value, which does not fit the 6 bytes of eui48::MacAddress
+pub struct MacAddr8([u8; 8]);
+
+impl<'a> FromSql<'a> for MacAddr8 {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<MacAddr8, Box<dyn Error + Sync + Send>> {
+        match <[u8; 8]>::try_from(raw) {
+            Ok(bytes) => Ok(MacAddr8(bytes)),
+            Err(_) => Err("invalid macaddr8: not 8 bytes".into()),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::MACADDR8
+    }
+}
+
+/// Formats like Postgres and eui48 do: lowercase hex bytes separated by colons
+impl std::fmt::Display for MacAddr8 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes: Vec<String> = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(f, "{}", bytes.join(":"))
+    }
+}
@@ -1,396 +1,465 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use chrono;
+use std::str::FromStr;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
 use tokio_postgres::{Row, types::Type};
-use bit_vec::BitVec;
-use chrono::Utc;
-use cidr;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::cmp::Ordering;
+use anyhow::Result;
 
-pub const NULL: &'static str = "Null";
+mod macaddr8;
+pub mod normalize;
+mod numeric;
+mod path;
+#[cfg(test)]
+pub mod test_support;
+mod value;
 
-fn str_as_name(name: &str) -> String {
-    format!("\"{}\"", name.replace("'", "''"))
+pub use value::{type_supported, Value};
+
+pub const NULL: &str = "Null";
+/// The text of Value::Undecodable
+pub const UNDECODABLE: &str = "Undecodable";
+
+/// The algorithm used to fingerprint rows.
+/// Both are stable across builds and machines, so fingerprints can be compared between runs.
+#[derive(Clone, Copy)]
+pub enum Fingerprint {
+    /// 128 bit xxHash (XXH3), fast
+    Xxh3,
+    /// SHA-256, truncated to 128 bits
+    Sha256,
 }
 
-fn varchar_as_sql_str(os: Option<String>) -> String {
-    match os {
-        Some(s) => format!("'{}'", s.replace("'", "''")),
-        None => String::from(NULL),
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Fingerprint> {
+        match s {
+            "xxh3" => Ok(Fingerprint::Xxh3),
+            "sha256" => Ok(Fingerprint::Sha256),
+            _ => Err(anyhow::anyhow!("Invalid fingerprint {}, use xxh3 or sha256", s)),
+        }
     }
 }
 
-fn float_as_sql_str(of: Option<f64>) -> String {
-    match of {
-        Some(f) => {
-            f.to_string()
+/// What to do with a column value that cannot be decoded
+#[derive(Clone, Copy, PartialEq)]
+pub enum DecodeErrors {
+    /// Stop the comparison with an error
+    Abort,
+    /// Use Value::Undecodable as the value, warn and go on
+    Record,
+}
+
+impl FromStr for DecodeErrors {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DecodeErrors> {
+        match s {
+            "abort" => Ok(DecodeErrors::Abort),
+            "record" => Ok(DecodeErrors::Record),
+            _ => Err(anyhow::anyhow!("Invalid decode errors policy {}, use abort or record", s)),
         }
-        None => String::from(NULL),
     }
 }
 
-fn point_as_sql_str(point: Option<geo_types::Point<f64>>) -> String {
-    match point {
-        Some(p) => format!("{} {}", p.x(), p.y()),
-        None => String::from(NULL),
-    }
+/// Settings that influence how rows are decoded and fingerprinted
+#[derive(Clone)]
+pub struct HashOptions {
+    pub fingerprint: Fingerprint,
+    /// Ignore trailing zeros in NUMERIC values, so that 1.50 and 1.5 are considered equal
+    pub trim_numeric_scale: bool,
+    /// Normalization rules for columns, see normalize::Rule
+    pub normalize: Vec<normalize::ColumnRule>,
+    /// Values of different types are never equal, see canonical_col
+    pub strict_types: bool,
+    pub decode_errors: DecodeErrors,
 }
 
-fn rect_as_sql_str(rect: Option<geo_types::Rect<f64>>) -> String {
-    match rect {
-        Some(r) => {
-            let c = r.center();
-            format!("{} {} {} {}", c.x, c.y, r.height(), r.width())
-        },
-        None => String::from(NULL),
+pub fn str_as_name(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quote a (possibly schema qualified) table name
+pub fn table_as_name(table_name: &str) -> String {
+    let parts: Vec<String> = table_name.splitn(2, '.').map(str_as_name).collect();
+    parts.join(".")
+}
+
+/// The canonical form of a value, so that logically equal values of compatible types compare equal,
+/// like those of a column that was migrated from timestamp to timestamptz or from char(n) to varchar.
+/// Most compatible types (integers, text types, json and jsonb) already decode to the same kind of value.
+fn canonical_col(col_type: &Type, val: Value) -> Value {
+    match val {
+        // Trailing spaces are insignificant in char(n), like they are in Postgres
+        Value::Text(s) if *col_type == Type::BPCHAR => Value::Text(String::from(s.trim_end_matches(' '))),
+        val => canonical_value(val),
     }
 }
 
-fn linestring_as_sql_str(ls: Option<geo_types::LineString<f64>>) -> String {
-    let mut vec: Vec<String> = Vec::new();
-    match ls {
-        Some(ls) => {
-            for c in ls.coords() {
-                vec.push(format!("{} {}", c.x, c.y))
-            }
-            vec.join(" ")
-        },
-        None => String::from(NULL),
+fn canonical_value(val: Value) -> Value {
+    match val {
+        // Timestamps without time zone are taken as UTC
+        Value::TimestampTz(dt) => Value::Timestamp(dt.naive_utc()),
+        Value::Float4(f) => Value::Float8(value::float4_as_f64(f)),
+        Value::Array(elements) => Value::Array(elements.into_iter().map(canonical_value).collect()),
+        val => val,
     }
 }
 
-fn json_as_sql_str(sj: Option<serde_json::Value>) -> String {
-    match sj {
-        Some(j) => j.to_string(),
-        None => String::from(NULL),
+fn trim_numeric_scale(val: Value) -> Value {
+    match val {
+        Value::Numeric(s) => Value::Numeric(numeric::trim_scale(&s)),
+        Value::Array(elements) => Value::Array(elements.into_iter().map(trim_numeric_scale).collect()),
+        val => val,
     }
 }
 
-fn col_as_sql_str(row: &Row, i: usize, display: bool) -> String {
-    let mut val_array: Vec<String>;
-    let col = &row.columns()[i];
-    match *col.type_() {
-        Type::BIT => {
-            match row.get::<usize, Option<BitVec>>(i) {
-                Some(b) => b.any().to_string(),
-                None => String::from(NULL),
-            }
-        },
-        Type::BIT_ARRAY => {
-            val_array = Vec::new();
-            for ob in row.get::<usize, Vec<Option<BitVec>>>(i) {
-                match ob {
-                    Some(b) => val_array.push(b.any().to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::BOOL => {
-            match row.get::<usize, Option<bool>>(i) {
-                Some(b) => b.to_string(),
-                None => String::from(NULL),
-            }
-        },
-        Type::BOOL_ARRAY => {
-            val_array = Vec::new();
-            for ob in row.get::<usize, Vec<Option<bool>>>(i) {
-                match ob {
-                    Some(b) => val_array.push(b.to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::CHAR =>
-            match row.get::<usize, Option<i8>>(i) {
-                Some(i) => i.to_string(),
-                None => String::from(NULL),
-            }
-        Type::CHAR_ARRAY => {
-            val_array = Vec::new();
-            for oi in row.get::<usize, Vec<Option<i8>>>(i) {
-                match oi {
-                    Some(i) => val_array.push(i.to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::INT2 =>
-            match row.get::<usize, Option<i16>>(i) {
-                Some(i) => i.to_string(),
-                None => String::from(NULL),
-            }
-        Type::INT2_ARRAY => {
-            val_array = Vec::new();
-            for oi in row.get::<usize, Vec<Option<i16>>>(i) {
-                match oi {
-                    Some(i) => val_array.push(i.to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::INT4 =>
-            match row.get::<usize, Option<i32>>(i) {
-                Some(i) => i.to_string(),
-                None => String::from(NULL),
-            }
-        Type::INT4_ARRAY => {
-            val_array = Vec::new();
-            for oi in row.get::<usize, Vec<Option<i32>>>(i) {
-                match oi {
-                    Some(i) => val_array.push(i.to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::INT8 =>
-            match row.get::<usize, Option<i64>>(i) {
-                Some(i) => i.to_string(),
-                None => String::from(NULL),
-            }
-        Type::INT8_ARRAY => {
-            val_array = Vec::new();
-            for oi in row.get::<usize, Vec<Option<i64>>>(i) {
-                match oi {
-                    Some(i) => val_array.push(i.to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::OID =>
-            match row.get::<usize, Option<u32>>(i) {
-                Some(i) => i.to_string(),
-                None => String::from(NULL),
-            }
-        Type::OID_ARRAY => {
-            val_array = Vec::new();
-            for oi in row.get::<usize, Vec<Option<u32>>>(i) {
-                match oi {
-                    Some(i) => val_array.push(i.to_string()),
-                    None => val_array.push(String::from(NULL))
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::FLOAT4 | Type::FLOAT8 => float_as_sql_str(row.get::<usize, Option<f64>>(i)),
-        Type::FLOAT4_ARRAY | Type::FLOAT8_ARRAY => {
-            val_array = Vec::new();
-            for f in row.get::<usize, Vec<Option<f64>>>(i) {
-                val_array.push(float_as_sql_str(f));
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::CIDR =>
-            match row.get::<usize, Option<cidr::IpCidr>>(i) {
-                Some(ic) => ic.to_string(),
-                None => String::from(NULL),
-            }
-        Type::CIDR_ARRAY => {
-            val_array = Vec::new();
-            for c in row.get::<usize, Vec<Option<cidr::IpCidr>>>(i) {
-                match c {
-                    Some(ic) => val_array.push(ic.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::INET =>
-            match row.get::<usize, Option<cidr::IpInet>>(i) {
-                Some(inet) => inet.to_string(),
-                None => String::from(NULL),
-            }
-        Type::INET_ARRAY => {
-            val_array = Vec::new();
-            for oinet in row.get::<usize, Vec<Option<cidr::IpInet>>>(i) {
-                match oinet {
-                    Some(inet) => val_array.push(inet.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::MACADDR | Type::MACADDR8 =>
-            match row.get::<usize, Option<eui48::MacAddress>>(i) {
-                Some(mac) => mac.to_string(eui48::MacAddressFormat::HexString),
-                None => String::from(NULL),
-            }
-        Type::MACADDR_ARRAY | Type::MACADDR8_ARRAY => {
-            val_array = Vec::new();
-            for m in row.get::<usize, Vec<Option<eui48::MacAddress>>>(i) {
-                match m {
-                    Some(mac) => val_array.push(mac.to_string(eui48::MacAddressFormat::HexString)),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::POINT => point_as_sql_str(row.get::<usize, Option<geo_types::Point<f64>>>(i)),
-        Type::POINT_ARRAY => {
-            val_array = Vec::new();
-            for p in row.get::<usize, Vec<Option<geo_types::Point<f64>>>>(i) {
-                val_array.push(point_as_sql_str(p))
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::BOX => rect_as_sql_str(row.get::<usize, Option<geo_types::Rect<f64>>>(i)),
-        Type::BOX_ARRAY => {
-            val_array = Vec::new();
-            for ob in row.get::<usize, Vec<Option<geo_types::Rect<f64>>>>(i) {
-                val_array.push(rect_as_sql_str(ob));
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::PATH => linestring_as_sql_str(row.get::<usize, Option<geo_types::LineString<f64>>>(i)),
-        Type::PATH_ARRAY => {
-            val_array = Vec::new();
-            for ls in row.get::<usize, Vec<Option<geo_types::LineString<f64>>>>(i) {
-                val_array.push(linestring_as_sql_str(ls));
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::JSON | Type::JSONB => json_as_sql_str(row.get::<usize, Option<serde_json::Value>>(i)),
-        Type::JSON_ARRAY | Type::JSONB_ARRAY => {
-            val_array = Vec::new();
-            for sj in row.get::<usize, Vec<Option<serde_json::Value>>>(i) {
-                val_array.push(json_as_sql_str(sj));
-            }
-            format!("[ {} ]", val_array.join(", "))
+/// A column value in the form it is fingerprinted and compared in: normalized according to the hash options
+/// and canonical. With strict types the type is part of it, so values of different types never compare equal.
+fn comparable_col(col: &RecordColumn, val: &Value, options: &HashOptions) -> (Value, Option<Type>) {
+    let val = if options.trim_numeric_scale { trim_numeric_scale(val.clone()) } else { val.clone() };
+    let val = normalize::apply(&options.normalize, &col.name, &col.type_, val);
+    let type_ = if options.strict_types { Some(col.type_.clone()) } else { None };
+    (canonical_col(&col.type_, val), type_)
+}
+
+/// The state of a fingerprint while the column values are fed to it
+enum RowDigest {
+    Xxh3(Box<Xxh3>),
+    Sha256(Sha256),
+}
+
+impl RowDigest {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            RowDigest::Xxh3(h) => h.update(bytes),
+            RowDigest::Sha256(h) => h.update(bytes),
         }
+    }
 
-        Type::UUID =>
-            match row.get::<usize, Option<uuid::Uuid>>(i) {
-                Some(mac) => mac.to_string(),
-                None => String::from(NULL),
-            }
-        Type::UUID_ARRAY => {
-            val_array = Vec::new();
-            for ou in row.get::<usize, Vec<Option<uuid::Uuid>>>(i) {
-                match ou {
-                    Some(u) => val_array.push(u.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
+    fn finish(self) -> u128 {
+        match self {
+            RowDigest::Xxh3(h) => h.digest128(),
+            RowDigest::Sha256(h) => {
+                let digest = h.finalize();
+                let mut truncated = [0u8; 16];
+                truncated.copy_from_slice(&digest[..16]);
+                u128::from_be_bytes(truncated)
+            },
         }
-        Type::TIMESTAMP =>
-            match row.get::<usize, Option<chrono::NaiveDateTime>>(i) {
-                Some(dt) => dt.to_string(),
-                None => String::from(NULL),
-            }
-        Type::TIMESTAMP_ARRAY => {
-            val_array = Vec::new();
-            for odt in row.get::<usize, Vec<Option<chrono::NaiveDateTime>>>(i) {
-                match odt {
-                    Some(dt) => val_array.push(dt.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::TIMESTAMPTZ =>
-            match row.get::<usize, Option<chrono::DateTime<Utc>>>(i) {
-                Some(dt) => dt.to_string(),
-                None => String::from(NULL),
-            }
-        Type::TIMESTAMPTZ_ARRAY => {
-            val_array = Vec::new();
-            for t in row.get::<usize, Vec<Option<chrono::DateTime<Utc>>>>(i) {
-                match t {
-                    Some(dt) => val_array.push(dt.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::DATE =>
-            match row.get::<usize, Option<chrono::NaiveDate>>(i) {
-                Some(dt) => dt.to_string(),
-                None => String::from(NULL),
-            }
-        Type::DATE_ARRAY => {
-            val_array = Vec::new();
-            for d in row.get::<usize, Vec<Option<chrono::NaiveDate>>>(i) {
-                match d {
-                    Some(dt) => val_array.push(dt.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-        },
-        Type::TIME =>
-            match row.get::<usize, Option<chrono::NaiveTime>>(i) {
-                Some(dt) => dt.to_string(),
-                None => String::from(NULL),
-            }
-        Type::TIME_ARRAY => {
-            val_array = Vec::new();
-            for d in row.get::<usize, Vec<Option<chrono::NaiveTime>>>(i) {
-                match d {
-                    Some(dt) => val_array.push(dt.to_string()),
-                    None => val_array.push(String::from(NULL)),
-                }
-            }
-            format!("[ {} ]", val_array.join(", "))
-
-        },
-        Type::VARCHAR | Type::BYTEA | Type::NAME | Type::TEXT => varchar_as_sql_str(row.get::<usize, Option<String>>(i)),
-        Type::VARCHAR_ARRAY | Type::BYTEA_ARRAY | Type::NAME_ARRAY | Type::TEXT_ARRAY => {
-            val_array = Vec::new();
-            for os in row.get::<usize, Vec<Option<String>>>(i) {
-                val_array.push(varchar_as_sql_str(os));
-            }
-            format!("[ {} ]", val_array.join(", "))
+    }
+}
+
+/// Fingerprint a row, from the identity of its comparable column values (see Value::encode).
+/// The values are fed to the digest one by one, so large (bytea) values are not copied.
+pub fn row_hasher(record: &Record, options: &HashOptions) -> u128 {
+    let mut digest = match options.fingerprint {
+        Fingerprint::Xxh3 => RowDigest::Xxh3(Box::new(Xxh3::new())),
+        Fingerprint::Sha256 => RowDigest::Sha256(Sha256::new()),
+    };
+    for (col, val) in record.iter() {
+        let (val, type_) = comparable_col(col, val, options);
+        val.encode(&mut |bytes| digest.update(bytes));
+        if let Some(type_) = type_ {
+            Value::Text(String::from(type_.name())).encode(&mut |bytes| digest.update(bytes));
         }
-        _ => {
-            if display {
-                println!("missing type conversion for {}, use {}::TEXT if you want to take it into account", *col.type_(), col.name());
+    }
+    digest.finish()
+}
+
+/// A column of a Record
+pub struct RecordColumn {
+    pub name: String,
+    pub type_: Type,
+}
+
+/// A row with its decoded column values, so that it can be kept around (or spilled to disk)
+/// without the tokio_postgres::Row. Rows of the same query share their columns.
+#[derive(Clone)]
+pub struct Record {
+    pub columns: Arc<Vec<RecordColumn>>,
+    pub values: Vec<Value>,
+}
+
+impl Record {
+    /// The columns of a row, to share between the records of a query
+    pub fn columns_of(row: &Row) -> Arc<Vec<RecordColumn>> {
+        Arc::new(row.columns().iter()
+            .map(|c| RecordColumn { name: String::from(c.name()), type_: c.type_().clone() })
+            .collect())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&RecordColumn, &Value)> {
+        self.columns.iter().zip(self.values.iter())
+    }
+}
+
+/// Decodes the rows of the query of one side into records, which share the columns of the query.
+/// Rows are numbered from 1 in the order they are decoded, to point out values that cannot be decoded.
+pub struct RowDecoder {
+    side: &'static str,
+    decode_errors: DecodeErrors,
+    columns: Option<Arc<Vec<RecordColumn>>>,
+    rows: u64,
+}
+
+impl RowDecoder {
+    pub fn new(side: &'static str, options: &HashOptions) -> RowDecoder {
+        RowDecoder { side, decode_errors: options.decode_errors, columns: None, rows: 0 }
+    }
+
+    pub fn decode(&mut self, row: &Row) -> Result<Record> {
+        self.rows += 1;
+        let columns = self.columns.get_or_insert_with(|| Record::columns_of(row)).clone();
+        let mut values: Vec<Value> = Vec::with_capacity(row.len());
+        for i in 0..row.len() {
+            match Value::decode(row, i) {
+                Ok(val) => values.push(val),
+                Err(e) => {
+                    let col = &row.columns()[i];
+                    let message = format!("Could not decode {} row {}, column {} of type {}",
+                                          self.side, self.rows, col.name(), col.type_());
+                    if self.decode_errors == DecodeErrors::Abort {
+                        return Err(e.context(message));
+                    }
+                    eprintln!("Warning: {}: {:#}", message, e);
+                    values.push(Value::Undecodable);
+                },
             }
-            String::from(NULL)
         }
+        Ok(Record { columns, values })
     }
 }
 
-pub fn row_hasher(row: &Row, display: bool) -> u64 {
-    let mut s = DefaultHasher::new();
+/// The column values of a record as they are shown in reports
+pub fn row_map(record: &Record) -> HashMap<String, String> {
+    record.iter().map(|(col, val)| (col.name.clone(), val.to_string())).collect()
+}
+
+/// The column values of a record in the form they are compared in, see comparable_col
+pub fn normalized_row_map(record: &Record, options: &HashOptions) -> HashMap<String, (Value, Option<Type>)> {
+    record.iter()
+        .map(|(col, val)| (col.name.clone(), comparable_col(col, val, options)))
+        .collect()
+}
 
-    let cols = row.columns();
-    for i in 0..cols.len() {
-        col_as_sql_str(row, i, display).hash(&mut s);
-        }
-    s.finish()
+pub fn row_as_string(record: &Record) -> String {
+    let col_vals: Vec<String> = record.iter().map(|(col, val)| format!("{}: {}", col.name, val)).collect();
+    format!("[ {} ]", col_vals.join(", "))
 }
 
-pub fn row_map(row: &Row, display: bool) -> HashMap<String, String> {
-    let mut row_map: HashMap<String, String> = HashMap::new();
-    let cols = row.columns();
-    for i in 0..cols.len() {
-        row_map.insert(String::from(cols[i].name()), col_as_sql_str(row, i, display));
+/// The columns of a row with typed JSON values
+pub fn row_as_json(record: &Record) -> serde_json::Map<String, serde_json::Value> {
+    record.iter().map(|(col, val)| (col.name.clone(), val.as_json())).collect()
+}
+
+/// Comment out a statement that uses an undecodable value (see DecodeErrors::Record), which has no literal:
+/// running it would fail, or refer to a column named Undecodable in a where clause or set list
+fn unless_undecodable<'a>(statement: String, mut values: impl Iterator<Item = &'a Value>) -> String {
+    if values.any(|val| matches!(val, Value::Undecodable)) {
+        format!("-- Warning: left out, the row has undecodable values: {}", statement)
+    } else {
+        statement
     }
-    row_map
 }
-pub fn row_as_string(row: &Row, display: bool) -> String {
-    let mut col_vals: Vec<String> = Vec::new();
-    let cols = row.columns();
-    for i in 0..cols.len() {
-        let col_val = format!("{}: {}", cols[i].name(), col_as_sql_str(row, i, display));
-        col_vals.push(col_val);
+
+fn key_values<'a>(key_columns: &'a [String], record: &'a Record) -> impl Iterator<Item = &'a Value> {
+    record.iter().filter(move |(col, _val)| key_columns.contains(&col.name)).map(|(_col, val)| val)
+}
+
+fn key_as_where_clause(key_columns: &[String], record: &Record) -> String {
+    let mut conditions: Vec<String> = Vec::new();
+    for key_col in key_columns {
+        if let Some((col, val)) = record.iter().find(|(col, _val)| &col.name == key_col) {
+            if let Value::Null = val {
+                conditions.push(format!("{} is null", str_as_name(key_col)));
+            } else {
+                conditions.push(format!("{} = {}", str_as_name(key_col), val.as_sql_literal(&col.type_)));
+            }
+        }
     }
-    format!("[ {} ]", col_vals.join(", "))
+    conditions.join(" and ")
 }
-pub fn row_as_insert(table_name: &str, row: &Row, display: bool) -> String {
+
+pub fn row_as_insert(table_name: &str, record: &Record) -> String {
     let mut col_names: Vec<String> = Vec::new();
     let mut col_vals: Vec<String> = Vec::new();
-    let cols = row.columns();
-    for i in 0..cols.len() {
-        col_names.push(str_as_name(cols[i].name()));
-        col_vals.push(col_as_sql_str(row, i, display));
+    for (col, val) in record.iter() {
+        col_names.push(str_as_name(&col.name));
+        col_vals.push(val.as_sql_literal(&col.type_));
+    }
+    let statement = format!("insert into {} ({}) VALUES({});", table_as_name(table_name),
+                            col_names.join(", "), col_vals.join(", "));
+    unless_undecodable(statement, record.values.iter())
+}
+
+/// Render an update that sets the given columns to the values in record, for the row with the same key
+pub fn row_as_update(table_name: &str, key_columns: &[String], record: &Record, set_columns: &[&str]) -> String {
+    let mut assignments: Vec<String> = Vec::new();
+    for (col, val) in record.iter() {
+        if set_columns.contains(&col.name.as_str()) {
+            assignments.push(format!("{} = {}", str_as_name(&col.name), val.as_sql_literal(&col.type_)));
+        }
+    }
+    let statement = format!("update {} set {} where {};", table_as_name(table_name), assignments.join(", "),
+                            key_as_where_clause(key_columns, record));
+    let set_values = record.iter().filter(|(col, _val)| set_columns.contains(&col.name.as_str())).map(|(_col, val)| val);
+    unless_undecodable(statement, set_values.chain(key_values(key_columns, record)))
+}
+
+/// Render a delete for the row with the same key as record
+pub fn row_as_delete(table_name: &str, key_columns: &[String], record: &Record) -> String {
+    let statement = format!("delete from {} where {};", table_as_name(table_name), key_as_where_clause(key_columns, record));
+    unless_undecodable(statement, key_values(key_columns, record))
+}
+
+fn key_value<'a>(record: &'a Record, name: &str) -> Result<(&'a RecordColumn, &'a Value)> {
+    match record.iter().find(|(col, _val)| col.name == name) {
+        Some(col_val) => Ok(col_val),
+        None => Err(anyhow::anyhow!("key column {} is not part of the query result", name)),
+    }
+}
+
+/// The key columns of a record in the form they are compared in (see comparable_col), without their types:
+/// keys pair up by value, and with strict types a difference in type is reported as a change of the column
+pub fn normalized_key(key_columns: &[String], record: &Record, options: &HashOptions) -> Result<Vec<Value>> {
+    key_columns.iter()
+        .map(|key_col| key_value(record, key_col).map(|(col, val)| comparable_col(col, val, options).0))
+        .collect()
+}
+
+/// Compare normalized keys by their values (nulls last), and only find them equal when they are
+pub fn normalized_key_cmp(a: &[Value], b: &[Value]) -> Ordering {
+    for (a_val, b_val) in a.iter().zip(b.iter()) {
+        match a_val.pair_cmp(b_val) {
+            Ordering::Equal => continue,
+            ord => return ord,
+        }
+    }
+    Ordering::Equal
+}
+
+/// Compare normalized keys in ORDER BY order (nulls last), to check that a sorted query is ordered.
+/// Unlike normalized_key_cmp, values that Postgres sorts as equal (1.5 and 1.50) are equal.
+/// Text is compared bytewise, so text keys should be sorted with COLLATE "C".
+pub fn normalized_key_order(a: &[Value], b: &[Value]) -> Ordering {
+    for (a_val, b_val) in a.iter().zip(b.iter()) {
+        match a_val.sort_cmp(b_val) {
+            Ordering::Equal => continue,
+            ord => return ord,
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use super::*;
+    use super::test_support::{options, record, text};
+
+    fn timestamp(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").unwrap()
+    }
+
+    #[test]
+    fn fingerprints() {
+        let a = record(&[("id", Type::INT4), ("name", Type::TEXT)], vec![Value::Int(1), text("a")]);
+        let b = record(&[("id", Type::INT4), ("name", Type::TEXT)], vec![Value::Int(1), text("b")]);
+        let null = record(&[("id", Type::INT4), ("name", Type::TEXT)], vec![Value::Int(1), Value::Null]);
+        let null_text = record(&[("id", Type::INT4), ("name", Type::TEXT)], vec![Value::Int(1), text("Null")]);
+        assert_eq!(row_hasher(&a, &options()), row_hasher(&a.clone(), &options()));
+        assert_ne!(row_hasher(&a, &options()), row_hasher(&b, &options()));
+        assert_ne!(row_hasher(&null, &options()), row_hasher(&null_text, &options()));
+        let sha256 = HashOptions { fingerprint: Fingerprint::Sha256, ..options() };
+        assert_ne!(row_hasher(&a, &sha256), row_hasher(&b, &sha256));
+    }
+
+    #[test]
+    fn compatible_types() {
+        let ts = timestamp("2020-01-02 03:04:05.123456");
+        let source = record(&[("id", Type::INT4), ("code", Type::BPCHAR), ("at", Type::TIMESTAMP), ("x", Type::FLOAT4)],
+                            vec![Value::Int(1), text("ab  "), Value::Timestamp(ts), Value::Float4(0.1)]);
+        let dest = record(&[("id", Type::INT8), ("code", Type::VARCHAR), ("at", Type::TIMESTAMPTZ), ("x", Type::FLOAT8)],
+                          vec![Value::Int(1), text("ab"), Value::TimestampTz(ts.and_utc()), Value::Float8(0.1)]);
+        assert_eq!(row_hasher(&source, &options()), row_hasher(&dest, &options()));
+        assert!(normalized_row_map(&source, &options()) == normalized_row_map(&dest, &options()));
+        let strict = HashOptions { strict_types: true, ..options() };
+        assert_ne!(row_hasher(&source, &strict), row_hasher(&dest, &strict));
+    }
+
+    #[test]
+    fn normalization() {
+        let columns = [("name", Type::TEXT), ("at", Type::TIMESTAMP), ("amount", Type::NUMERIC), ("xs", Type::FLOAT8_ARRAY)];
+        let source = record(&columns, vec![
+            text(" Ab "), Value::Timestamp(timestamp("2020-01-02 03:04:05.4")),
+            Value::Numeric(String::from("1.50")), Value::Array(vec![Value::Float8(1.0001), Value::Null]),
+        ]);
+        let dest = record(&columns, vec![
+            text("ab"), Value::Timestamp(timestamp("2020-01-02 03:04:04.6")),
+            Value::Numeric(String::from("1.5")), Value::Array(vec![Value::Float8(1.0002), Value::Null]),
+        ]);
+        assert_ne!(row_hasher(&source, &options()), row_hasher(&dest, &options()));
+        let rules = ["name:trim", "name:lowercase", "at:round=s", "xs:digits=3"];
+        let normalizing = HashOptions {
+            trim_numeric_scale: true,
+            normalize: rules.iter().map(|rule| rule.parse().unwrap()).collect(),
+            ..options()
+        };
+        assert_eq!(row_hasher(&source, &normalizing), row_hasher(&dest, &normalizing));
+        // Reports keep the original values
+        assert_eq!(row_map(&source)["name"], "' Ab '");
+    }
+
+    #[test]
+    fn statements() {
+        let row = record(&[("id", Type::INT4), ("name", Type::TEXT), ("born", Type::DATE), ("photo", Type::BYTEA)],
+                         vec![Value::Int(7), text("O'Neil"), Value::Date(NaiveDate::from_ymd_opt(1990, 5, 1).unwrap()),
+                              Value::Bytes(vec![0xca, 0xfe])]);
+        assert_eq!(row_as_insert("public.people", &row),
+                   "insert into \"public\".\"people\" (\"id\", \"name\", \"born\", \"photo\") \
+                    VALUES(7, 'O''Neil', '1990-05-01'::date, '\\xcafe');");
+        assert_eq!(row_as_update("people", &[String::from("id")], &row, &["name"]),
+                   "update \"people\" set \"name\" = 'O''Neil' where \"id\" = 7;");
+        let unknown = record(&[("id", Type::INT4)], vec![Value::Null]);
+        assert_eq!(row_as_delete("people", &[String::from("id")], &unknown), "delete from \"people\" where \"id\" is null;");
+        assert_eq!(row_as_string(&row), "[ id: 7, name: 'O''Neil', born: 1990-05-01, photo: '\\xcafe' ]");
+        assert_eq!(serde_json::Value::Object(row_as_json(&row)),
+                   serde_json::json!({"id": 7, "name": "O'Neil", "born": "1990-05-01", "photo": "yv4="}));
+    }
+
+    #[test]
+    fn key_order() {
+        let key = [String::from("id"), String::from("code")];
+        let key_of = |row: &Record| normalized_key(&key, row, &options()).unwrap();
+        let columns = [("id", Type::INT4), ("code", Type::BPCHAR)];
+        let a = key_of(&record(&columns, vec![Value::Int(2), text("b ")]));
+        let b = key_of(&record(&columns, vec![Value::Int(10), text("a")]));
+        let c = key_of(&record(&[("id", Type::INT8), ("code", Type::VARCHAR)], vec![Value::Int(2), text("b")]));
+        let d = key_of(&record(&columns, vec![Value::Null, text("a")]));
+        assert_eq!(normalized_key_order(&a, &b), Ordering::Less);
+        assert_eq!(normalized_key_order(&a, &c), Ordering::Equal);
+        assert_eq!(normalized_key_order(&d, &b), Ordering::Greater);
+        let a = record(&columns, vec![Value::Int(2), text("b ")]);
+        assert!(normalized_key(&[String::from("missing")], &a, &options()).is_err());
+    }
+
+    #[test]
+    fn numeric_key_order() {
+        let key = [String::from("id")];
+        let columns = [("id", Type::NUMERIC)];
+        let key_of = |n: &str| normalized_key(&key, &record(&columns, vec![Value::Numeric(String::from(n))]), &options()).unwrap();
+        let ordered = ["-Infinity", "-10.5", "-9", "-0.25", "0.00", "0.0001", "1.50", "9", "10", "Infinity", "NaN"];
+        for pair in ordered.windows(2) {
+            let (a, b) = (key_of(pair[0]), key_of(pair[1]));
+            assert_eq!(normalized_key_order(&a, &b), Ordering::Less, "{} < {}", pair[0], pair[1]);
+            assert_eq!(normalized_key_order(&b, &a), Ordering::Greater, "{} > {}", pair[1], pair[0]);
+            assert_eq!(normalized_key_cmp(&a, &b), Ordering::Less, "{} < {}", pair[0], pair[1]);
+        }
+        // Sorted the same, but only paired when the scale is trimmed
+        assert_eq!(normalized_key_order(&key_of("1.50"), &key_of("1.5")), Ordering::Equal);
+        assert_ne!(normalized_key_cmp(&key_of("1.50"), &key_of("1.5")), Ordering::Equal);
     }
-    format!("insert into {} ({}) VALUES({});", str_as_name(table_name),
-            col_names.join(", "), col_vals.join(", "))
 }
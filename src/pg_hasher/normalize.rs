@@ -0,0 +1,189 @@
+use std::str::FromStr;
+use anyhow::Result;
+use chrono::{DurationRound, NaiveDate, TimeDelta};
+use tokio_postgres::Column;
+use tokio_postgres::types::Type;
+use super::value::{float4_as_f64, Value};
+
+/// A normalization of column values, applied before rows are fingerprinted and compared.
+/// Only the comparison uses normalized values, the reported rows keep their original values.
+#[derive(Clone, PartialEq)]
+pub enum Rule {
+    /// Remove leading and trailing whitespace from text
+    Trim,
+    /// Lowercase text
+    Lowercase,
+    /// Round timestamps and times to a number of microseconds
+    Round(i64),
+    /// Round floats to the nearest multiple of epsilon. This is not a tolerance: values closer than epsilon
+    /// can round to different multiples (0.0049 and 0.0051 with epsilon 0.01), and only values that round
+    /// to the same multiple are equal
+    Epsilon(f64),
+    /// Round floats to a number of significant digits
+    Digits(usize),
+    /// Parse text as JSON and order the keys of objects (json and jsonb values are always ordered)
+    SortKeys,
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Rule> {
+        let (name, arg) = s.split_once('=').unwrap_or((s, ""));
+        match (name, arg) {
+            ("trim", "") => Ok(Rule::Trim),
+            ("lowercase", "") => Ok(Rule::Lowercase),
+            ("round", unit) => match unit {
+                "ms" => Ok(Rule::Round(1_000)),
+                "s" => Ok(Rule::Round(1_000_000)),
+                "min" => Ok(Rule::Round(60_000_000)),
+                "h" => Ok(Rule::Round(3_600_000_000)),
+                "d" => Ok(Rule::Round(86_400_000_000)),
+                _ => Err(anyhow::anyhow!("Invalid unit {} for round, use ms, s, min, h or d", unit)),
+            },
+            ("epsilon", epsilon) => match epsilon.parse::<f64>() {
+                Ok(e) if e > 0.0 && e.is_finite() => Ok(Rule::Epsilon(e)),
+                _ => Err(anyhow::anyhow!("Invalid epsilon {}, use a positive number", epsilon)),
+            },
+            ("digits", digits) => match digits.parse::<usize>() {
+                Ok(d) if d > 0 => Ok(Rule::Digits(d)),
+                _ => Err(anyhow::anyhow!("Invalid digits {}, use a positive number", digits)),
+            },
+            ("sort-keys", "") => Ok(Rule::SortKeys),
+            _ => Err(anyhow::anyhow!(
+                "Invalid normalization rule {}, use trim, lowercase, round=<unit>, epsilon=<e>, digits=<n> or sort-keys", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::Trim => write!(f, "trim"),
+            Rule::Lowercase => write!(f, "lowercase"),
+            Rule::Round(us) => {
+                let unit = match us {
+                    1_000 => "ms",
+                    1_000_000 => "s",
+                    60_000_000 => "min",
+                    3_600_000_000 => "h",
+                    _ => "d",
+                };
+                write!(f, "round={}", unit)
+            },
+            Rule::Epsilon(e) => write!(f, "epsilon={}", e),
+            Rule::Digits(d) => write!(f, "digits={}", d),
+            Rule::SortKeys => write!(f, "sort-keys"),
+        }
+    }
+}
+
+impl Rule {
+    /// Whether the rule applies to values of a type
+    fn applies_to(&self, col_type: &Type) -> bool {
+        match self {
+            Rule::Trim | Rule::Lowercase | Rule::SortKeys => matches!(*col_type,
+                Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::TEXT),
+            Rule::Round(_) => matches!(*col_type,
+                Type::TIMESTAMP | Type::TIMESTAMPTZ | Type::TIME |
+                Type::TIMESTAMP_ARRAY | Type::TIMESTAMPTZ_ARRAY | Type::TIME_ARRAY),
+            Rule::Epsilon(_) | Rule::Digits(_) => matches!(*col_type,
+                Type::FLOAT4 | Type::FLOAT8 | Type::FLOAT4_ARRAY | Type::FLOAT8_ARRAY),
+        }
+    }
+
+    /// Whether normalized values keep the order of the original ones (values may become equal, but never swap),
+    /// so that a query sorted by the original values is sorted by the normalized ones too
+    fn keeps_order(&self) -> bool {
+        !matches!(self, Rule::Trim | Rule::Lowercase | Rule::SortKeys)
+    }
+
+    /// Normalize a single (not an array) value
+    fn apply_scalar(&self, val: Value) -> Value {
+        match (self, val) {
+            (Rule::Trim, Value::Text(text)) => Value::Text(String::from(text.trim())),
+            (Rule::Lowercase, Value::Text(text)) => Value::Text(text.to_lowercase()),
+            (Rule::SortKeys, Value::Text(text)) => match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(json) => Value::Text(json.to_string()),
+                Err(_) => Value::Text(text),
+            },
+            (Rule::Round(us), Value::Timestamp(dt)) => Value::Timestamp(dt.duration_round(unit(*us)).unwrap_or(dt)),
+            (Rule::Round(us), Value::TimestampTz(dt)) => Value::TimestampTz(dt.duration_round(unit(*us)).unwrap_or(dt)),
+            (Rule::Round(us), Value::Time(t)) => match NaiveDate::default().and_time(t).duration_round(unit(*us)) {
+                Ok(dt) => Value::Time(dt.time()),
+                Err(_) => Value::Time(t),
+            },
+            (Rule::Epsilon(_) | Rule::Digits(_), Value::Float4(f)) => self.apply_scalar(Value::Float8(float4_as_f64(f))),
+            // The nearest number of epsilons is as good as the rounded value itself to compare with
+            (Rule::Epsilon(e), Value::Float8(f)) => Value::Float8((f / e).round()),
+            (Rule::Digits(d), Value::Float8(f)) if f.is_finite() =>
+                Value::Float8(format!("{:.*e}", d - 1, f).parse().unwrap_or(f)),
+            (_, val) => val,
+        }
+    }
+
+    fn apply(&self, val: Value) -> Value {
+        match val {
+            Value::Array(elements) => Value::Array(elements.into_iter().map(|e| self.apply_scalar(e)).collect()),
+            val => self.apply_scalar(val),
+        }
+    }
+}
+
+fn unit(us: i64) -> TimeDelta {
+    TimeDelta::microseconds(us)
+}
+
+/// A normalization rule for a column, or for all columns it applies to (*)
+#[derive(Clone, PartialEq)]
+pub struct ColumnRule {
+    pub column: String,
+    pub rule: Rule,
+}
+
+impl FromStr for ColumnRule {
+    type Err = anyhow::Error;
+
+    /// Parse column:rule
+    fn from_str(s: &str) -> Result<ColumnRule> {
+        match s.rsplit_once(':') {
+            Some((column, rule)) if !column.is_empty() => Ok(ColumnRule { column: String::from(column), rule: rule.parse()? }),
+            _ => Err(anyhow::anyhow!("Invalid normalization {}, use column:rule", s)),
+        }
+    }
+}
+
+/// Normalize a column value with the rules for the column, in the order they were given
+pub fn apply(rules: &[ColumnRule], name: &str, col_type: &Type, val: Value) -> Value {
+    rules.iter()
+        .filter(|r| (r.column == "*" || r.column == name) && r.rule.applies_to(col_type))
+        .fold(val, |val, r| r.rule.apply(val))
+}
+
+/// Check that the columns that rules are given for exist, and that the rules apply to their types
+pub fn check(rules: &[ColumnRule], side: &str, columns: &[Column]) -> Result<()> {
+    for r in rules.iter().filter(|r| r.column != "*") {
+        match columns.iter().find(|c| c.name() == r.column) {
+            None => return Err(anyhow::anyhow!(
+                "Column {} to normalize is not part of the {} query result", r.column, side)),
+            Some(col) if !r.rule.applies_to(col.type_()) => return Err(anyhow::anyhow!(
+                "Normalization {} does not apply to column {} of type {}", r.rule, r.column, col.type_())),
+            Some(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Check that the rules for the key columns of a sorted (merge join) comparison keep their order, see Rule::keeps_order
+pub fn check_sorted_keys(rules: &[ColumnRule], side: &str, columns: &[Column], key_columns: &[String]) -> Result<()> {
+    for col in columns.iter().filter(|c| key_columns.iter().any(|k| k == c.name())) {
+        let reorders = rules.iter()
+            .find(|r| (r.column == "*" || r.column == col.name()) && r.rule.applies_to(col.type_()) && !r.rule.keeps_order());
+        if let Some(r) = reorders {
+            return Err(anyhow::anyhow!(
+                "Normalization {} of key column {} on the {} side can change the order of the keys, \
+                 which a sorted (merge join) comparison requires. Leave out --sorted, or the rule for the key column", r.rule, col.name(), side));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+const NUMERIC_NEG: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// A NUMERIC value, decoded exactly from the binary wire format (base 10000 digits).
+/// Unlike f64 or rust_decimal it has no limits on precision or scale.
+pub struct PgNumeric {
+    sign: u16,
+    weight: i16,
+    dscale: u16,
+    digits: Vec<i16>,
+}
+
+fn read_u16(raw: &[u8], pos: usize) -> Result<u16, Box<dyn Error + Sync + Send>> {
+    match raw.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err("invalid numeric: message too short".into()),
+    }
+}
+
+impl<'a> FromSql<'a> for PgNumeric {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<PgNumeric, Box<dyn Error + Sync + Send>> {
+        let ndigits = read_u16(raw, 0)? as usize;
+        let weight = read_u16(raw, 2)? as i16;
+        let sign = read_u16(raw, 4)?;
+        let dscale = read_u16(raw, 6)?;
+        let mut digits: Vec<i16> = Vec::with_capacity(ndigits);
+        for d in 0..ndigits {
+            digits.push(read_u16(raw, 8 + 2 * d)? as i16);
+        }
+        Ok(PgNumeric { sign, weight, dscale, digits })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+}
+
+impl PgNumeric {
+    fn digit(&self, pos: i32) -> i16 {
+        if pos < 0 {
+            return 0;
+        }
+        *self.digits.get(pos as usize).unwrap_or(&0)
+    }
+}
+
+/// Formats like Postgres does: all digits up to the display scale, so 1.50 stays 1.50
+impl std::fmt::Display for PgNumeric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.sign {
+            NUMERIC_NAN => return write!(f, "NaN"),
+            NUMERIC_PINF => return write!(f, "Infinity"),
+            NUMERIC_NINF => return write!(f, "-Infinity"),
+            _ => (),
+        }
+        let mut s = String::new();
+        if self.sign == NUMERIC_NEG {
+            s.push('-');
+        }
+        let weight = self.weight as i32;
+        if weight < 0 {
+            s.push('0');
+        } else {
+            s.push_str(&self.digit(0).to_string());
+            for pos in 1..=weight {
+                s.push_str(&format!("{:04}", self.digit(pos)));
+            }
+        }
+        if self.dscale > 0 {
+            let mut fraction = String::new();
+            let mut pos = weight + 1;
+            while fraction.len() < self.dscale as usize {
+                fraction.push_str(&format!("{:04}", self.digit(pos)));
+                pos += 1;
+            }
+            fraction.truncate(self.dscale as usize);
+            s.push('.');
+            s.push_str(&fraction);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+/// Remove trailing zeros from the fraction of a formatted numeric, so that 1.50 and 1.5 compare equal
+pub fn trim_scale(val: &str) -> String {
+    if !val.contains('.') {
+        return String::from(val);
+    }
+    String::from(val.trim_end_matches('0').trim_end_matches('.'))
+}
+
+/// Compare formatted numerics in Postgres order: -Infinity, numbers, Infinity, then NaN
+pub fn cmp_text(a: &str, b: &str) -> Ordering {
+    fn rank(val: &str) -> u8 {
+        match val {
+            "-Infinity" => 0,
+            "Infinity" => 2,
+            "NaN" => 3,
+            _ => 1,
+        }
+    }
+    /// Whether the number is negative, its integer digits and its fraction digits, without insignificant zeros
+    fn parts(val: &str) -> (bool, &str, &str) {
+        let (negative, digits) = match val.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, val),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let (integer, fraction) = (integer.trim_start_matches('0'), fraction.trim_end_matches('0'));
+        (negative && !(integer.is_empty() && fraction.is_empty()), integer, fraction)
+    }
+    match (rank(a), rank(b)) {
+        (1, 1) => (),
+        (rank_a, rank_b) => return rank_a.cmp(&rank_b),
+    }
+    let (a_negative, a_integer, a_fraction) = parts(a);
+    let (b_negative, b_integer, b_fraction) = parts(b);
+    if a_negative != b_negative {
+        return b_negative.cmp(&a_negative);
+    }
+    let magnitude = a_integer.len().cmp(&b_integer.len())
+        .then_with(|| a_integer.cmp(b_integer))
+        .then_with(|| a_fraction.cmp(b_fraction));
+    if a_negative { magnitude.reverse() } else { magnitude }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of a numeric in the binary wire format
+    fn numeric(sign: u16, weight: i16, dscale: u16, digits: &[i16]) -> String {
+        let mut raw: Vec<u8> = Vec::new();
+        for field in [digits.len() as u16, weight as u16, sign, dscale] {
+            raw.extend_from_slice(&field.to_be_bytes());
+        }
+        for digit in digits {
+            raw.extend_from_slice(&digit.to_be_bytes());
+        }
+        PgNumeric::from_sql(&Type::NUMERIC, &raw).unwrap().to_string()
+    }
+
+    #[test]
+    fn special_values() {
+        assert_eq!(numeric(NUMERIC_NAN, 0, 0, &[]), "NaN");
+        assert_eq!(numeric(NUMERIC_PINF, 0, 0, &[]), "Infinity");
+        assert_eq!(numeric(NUMERIC_NINF, 0, 0, &[]), "-Infinity");
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(numeric(0, 0, 0, &[]), "0");
+        assert_eq!(numeric(0, 0, 2, &[]), "0.00");
+        assert_eq!(numeric(0, 1, 0, &[12, 3456]), "123456");
+        assert_eq!(numeric(NUMERIC_NEG, 1, 0, &[12, 3456]), "-123456");
+        // Digits after the last one are zeros, up to the display scale
+        assert_eq!(numeric(0, 0, 2, &[1, 5000]), "1.50");
+        assert_eq!(numeric(0, 0, 6, &[1, 5000]), "1.500000");
+        assert_eq!(numeric(0, 0, 1, &[1, 5678]), "1.5");
+        assert_eq!(numeric(NUMERIC_NEG, 0, 3, &[3, 1410]), "-3.141");
+        // Trailing zeros of the integer part are not stored
+        assert_eq!(numeric(0, 2, 0, &[7]), "700000000");
+        // A weight below zero: the first digit comes after the decimal point, and after more zeros below -1
+        assert_eq!(numeric(0, -1, 4, &[25]), "0.0025");
+        assert_eq!(numeric(NUMERIC_NEG, -1, 2, &[2500]), "-0.25");
+        assert_eq!(numeric(0, -2, 8, &[12]), "0.00000012");
+    }
+
+    #[test]
+    fn trim_numeric_scale() {
+        assert_eq!(trim_scale(&numeric(0, 0, 2, &[1, 5000])), "1.5");
+        assert_eq!(trim_scale(&numeric(0, 0, 2, &[1])), "1");
+        assert_eq!(trim_scale(&numeric(0, 0, 2, &[])), "0");
+        assert_eq!(trim_scale(&numeric(0, 2, 0, &[7])), "700000000");
+        assert_eq!(trim_scale("-0.2500"), "-0.25");
+        assert_eq!(trim_scale("NaN"), "NaN");
+    }
+}
@@ -0,0 +1,47 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+/// A PATH value. geo_types::LineString drops whether the path is closed, which is part of its value.
+pub struct Path {
+    closed: bool,
+    points: Vec<(f64, f64)>,
+}
+
+fn f64_at(raw: &[u8], offset: usize) -> Result<f64, Box<dyn Error + Sync + Send>> {
+    match raw.get(offset..offset + 8).map(<[u8; 8]>::try_from) {
+        Some(Ok(bytes)) => Ok(f64::from_be_bytes(bytes)),
+        _ => Err("invalid path: too short".into()),
+    }
+}
+
+impl<'a> FromSql<'a> for Path {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Path, Box<dyn Error + Sync + Send>> {
+        // A closed flag, the number of points, then the x and y of each point
+        if raw.len() < 5 {
+            return Err("invalid path: too short".into());
+        }
+        let closed = raw[0] != 0;
+        let count = u32::from_be_bytes([raw[1], raw[2], raw[3], raw[4]]) as usize;
+        if raw.len() != 5 + count * 16 {
+            return Err("invalid path: wrong number of points".into());
+        }
+        let points = (0..count)
+            .map(|i| Ok((f64_at(raw, 5 + i * 16)?, f64_at(raw, 13 + i * 16)?)))
+            .collect::<Result<_, Box<dyn Error + Sync + Send>>>()?;
+        Ok(Path { closed, points })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::PATH
+    }
+}
+
+/// Formats like Postgres does: [(x,y),...] when open, ((x,y),...) when closed
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let points: Vec<String> = self.points.iter().map(|(x, y)| format!("({},{})", x, y)).collect();
+        let (open, close) = if self.closed { ("(", ")") } else { ("[", "]") };
+        write!(f, "{}{}{}", open, points.join(","), close)
+    }
+}
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use tokio_postgres::types::Type;
+use super::{DecodeErrors, Fingerprint, HashOptions, Record, RecordColumn, Value};
+
+/// Hash options with every normalization off
+pub fn options() -> HashOptions {
+    HashOptions {
+        fingerprint: Fingerprint::Xxh3,
+        trim_numeric_scale: false,
+        normalize: Vec::new(),
+        strict_types: false,
+        decode_errors: DecodeErrors::Abort,
+    }
+}
+
+/// A record with columns of the given names and types
+pub fn record(columns: &[(&str, Type)], values: Vec<Value>) -> Record {
+    let columns = columns.iter()
+        .map(|(name, type_)| RecordColumn { name: String::from(*name), type_: type_.clone() })
+        .collect();
+    Record { columns: Arc::new(columns), values }
+}
+
+pub fn text(s: &str) -> Value {
+    Value::Text(String::from(s))
+}
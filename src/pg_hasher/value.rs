@@ -0,0 +1,550 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use bit_vec::BitVec;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Error, Row, types::{FromSql, Type}};
+use super::{macaddr8, numeric, path, NULL, UNDECODABLE};
+
+/// A column value, decoded once per cell. Fingerprints, comparisons, SQL literals and JSON are all
+/// derived from it; Display gives the text that reports show.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Value {
+    Null,
+    /// A value that could not be decoded, see DecodeErrors::Record
+    Undecodable,
+    Bool(bool),
+    /// bit and varbit, as a string of 0s and 1s
+    Bits(String),
+    /// "char", int2, int4, int8 and oid
+    Int(i64),
+    Float4(#[serde(with = "f32_bits")] f32),
+    Float8(#[serde(with = "f64_bits")] f64),
+    /// Exact, as Postgres prints it
+    Numeric(String),
+    /// varchar, bpchar, name and text
+    Text(String),
+    Bytes(#[serde(with = "base64_bytes")] Vec<u8>),
+    Timestamp(NaiveDateTime),
+    TimestampTz(DateTime<Utc>),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    /// json and jsonb, with the keys of objects ordered
+    Json(serde_json::Value),
+    Uuid(uuid::Uuid),
+    /// cidr, inet, macaddr and macaddr8, as text
+    Network(String),
+    /// point, box and path, as Postgres prints them
+    Geometric(String),
+    Array(Vec<Value>),
+}
+
+/// Floats are kept as their bits in spill files, as JSON has no NaN or infinity
+mod f32_bits {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(f: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(f.to_bits())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        Ok(f32::from_bits(u32::deserialize(deserializer)?))
+    }
+}
+
+mod f64_bits {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(f: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(f.to_bits())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        Ok(f64::from_bits(u64::deserialize(deserializer)?))
+    }
+}
+
+mod base64_bytes {
+    use base64::prelude::{BASE64_STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        BASE64_STANDARD.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A column value converted with f, or Null
+fn scalar<'a, T: FromSql<'a>>(row: &'a Row, i: usize, f: impl Fn(T) -> Value) -> Result<Value, Error> {
+    Ok(row.try_get::<usize, Option<T>>(i)?.map(f).unwrap_or(Value::Null))
+}
+
+/// An array column value with its elements converted with f, or Null
+fn array<'a, T: FromSql<'a>>(row: &'a Row, i: usize, f: impl Fn(T) -> Value) -> Result<Value, Error> {
+    Ok(match row.try_get::<usize, Option<Vec<Option<T>>>>(i)? {
+        Some(elements) => Value::Array(elements.into_iter().map(|e| e.map(&f).unwrap_or(Value::Null)).collect()),
+        None => Value::Null,
+    })
+}
+
+fn bits(b: BitVec) -> Value {
+    Value::Bits(b.iter().map(|bit| if bit { '1' } else { '0' }).collect())
+}
+
+fn point_as_str(p: geo_types::Point<f64>) -> String {
+    format!("({},{})", p.x(), p.y())
+}
+
+/// The upper right corner first, like Postgres
+fn rect_as_str(r: geo_types::Rect<f64>) -> String {
+    format!("({},{}),({},{})", r.max().x, r.max().y, r.min().x, r.min().y)
+}
+
+fn mac_as_str(mac: eui48::MacAddress) -> String {
+    mac.to_string(eui48::MacAddressFormat::HexString)
+}
+
+/// Whether Value::decode knows how to decode values of this type.
+/// Keep this in sync with the match arms in Value::decode.
+pub fn type_supported(col_type: &Type) -> bool {
+    matches!(*col_type,
+        Type::BIT | Type::BIT_ARRAY | Type::VARBIT | Type::VARBIT_ARRAY | Type::BOOL | Type::BOOL_ARRAY | Type::CHAR | Type::CHAR_ARRAY |
+        Type::INT2 | Type::INT2_ARRAY | Type::INT4 | Type::INT4_ARRAY | Type::INT8 | Type::INT8_ARRAY |
+        Type::OID | Type::OID_ARRAY | Type::NUMERIC | Type::NUMERIC_ARRAY |
+        Type::FLOAT4 | Type::FLOAT8 | Type::FLOAT4_ARRAY | Type::FLOAT8_ARRAY |
+        Type::CIDR | Type::CIDR_ARRAY | Type::INET | Type::INET_ARRAY |
+        Type::MACADDR | Type::MACADDR8 | Type::MACADDR_ARRAY | Type::MACADDR8_ARRAY |
+        Type::POINT | Type::POINT_ARRAY | Type::BOX | Type::BOX_ARRAY | Type::PATH | Type::PATH_ARRAY |
+        Type::JSON | Type::JSONB | Type::JSON_ARRAY | Type::JSONB_ARRAY | Type::UUID | Type::UUID_ARRAY |
+        Type::TIMESTAMP | Type::TIMESTAMP_ARRAY | Type::TIMESTAMPTZ | Type::TIMESTAMPTZ_ARRAY |
+        Type::DATE | Type::DATE_ARRAY | Type::TIME | Type::TIME_ARRAY |
+        Type::VARCHAR | Type::BPCHAR | Type::BYTEA | Type::NAME | Type::TEXT |
+        Type::VARCHAR_ARRAY | Type::BPCHAR_ARRAY | Type::BYTEA_ARRAY | Type::NAME_ARRAY | Type::TEXT_ARRAY)
+}
+
+/// The double that a real prints as, so that 0.1::real equals 0.1::double precision
+pub fn float4_as_f64(f: f32) -> f64 {
+    f.to_string().parse().unwrap_or(f as f64)
+}
+
+impl Value {
+    /// Decode a column value. Fails when the value cannot be decoded as the Rust type of its column type,
+    /// and for types that cannot be decoded at all, which would otherwise all compare equal.
+    pub fn decode(row: &Row, i: usize) -> anyhow::Result<Value> {
+        let val = match *row.columns()[i].type_() {
+            Type::BIT | Type::VARBIT => scalar(row, i, bits),
+            Type::BIT_ARRAY | Type::VARBIT_ARRAY => array(row, i, bits),
+            Type::BOOL => scalar(row, i, Value::Bool),
+            Type::BOOL_ARRAY => array(row, i, Value::Bool),
+            Type::CHAR => scalar(row, i, |c: i8| Value::Int(c as i64)),
+            Type::CHAR_ARRAY => array(row, i, |c: i8| Value::Int(c as i64)),
+            Type::INT2 => scalar(row, i, |n: i16| Value::Int(n as i64)),
+            Type::INT2_ARRAY => array(row, i, |n: i16| Value::Int(n as i64)),
+            Type::INT4 => scalar(row, i, |n: i32| Value::Int(n as i64)),
+            Type::INT4_ARRAY => array(row, i, |n: i32| Value::Int(n as i64)),
+            Type::INT8 => scalar(row, i, Value::Int),
+            Type::INT8_ARRAY => array(row, i, Value::Int),
+            Type::OID => scalar(row, i, |n: u32| Value::Int(n as i64)),
+            Type::OID_ARRAY => array(row, i, |n: u32| Value::Int(n as i64)),
+            Type::NUMERIC => scalar(row, i, |n: numeric::PgNumeric| Value::Numeric(n.to_string())),
+            Type::NUMERIC_ARRAY => array(row, i, |n: numeric::PgNumeric| Value::Numeric(n.to_string())),
+            Type::FLOAT4 => scalar(row, i, Value::Float4),
+            Type::FLOAT4_ARRAY => array(row, i, Value::Float4),
+            Type::FLOAT8 => scalar(row, i, Value::Float8),
+            Type::FLOAT8_ARRAY => array(row, i, Value::Float8),
+            Type::CIDR => scalar(row, i, |c: cidr::IpCidr| Value::Network(c.to_string())),
+            Type::CIDR_ARRAY => array(row, i, |c: cidr::IpCidr| Value::Network(c.to_string())),
+            Type::INET => scalar(row, i, |inet: cidr::IpInet| Value::Network(inet.to_string())),
+            Type::INET_ARRAY => array(row, i, |inet: cidr::IpInet| Value::Network(inet.to_string())),
+            Type::MACADDR => scalar(row, i, |mac: eui48::MacAddress| Value::Network(mac_as_str(mac))),
+            Type::MACADDR_ARRAY => array(row, i, |mac: eui48::MacAddress| Value::Network(mac_as_str(mac))),
+            Type::MACADDR8 => scalar(row, i, |mac: macaddr8::MacAddr8| Value::Network(mac.to_string())),
+            Type::MACADDR8_ARRAY => array(row, i, |mac: macaddr8::MacAddr8| Value::Network(mac.to_string())),
+            Type::POINT => scalar(row, i, |p| Value::Geometric(point_as_str(p))),
+            Type::POINT_ARRAY => array(row, i, |p| Value::Geometric(point_as_str(p))),
+            Type::BOX => scalar(row, i, |r| Value::Geometric(rect_as_str(r))),
+            Type::BOX_ARRAY => array(row, i, |r| Value::Geometric(rect_as_str(r))),
+            Type::PATH => scalar(row, i, |p: path::Path| Value::Geometric(p.to_string())),
+            Type::PATH_ARRAY => array(row, i, |p: path::Path| Value::Geometric(p.to_string())),
+            Type::JSON | Type::JSONB => scalar(row, i, Value::Json),
+            Type::JSON_ARRAY | Type::JSONB_ARRAY => array(row, i, Value::Json),
+            Type::UUID => scalar(row, i, Value::Uuid),
+            Type::UUID_ARRAY => array(row, i, Value::Uuid),
+            Type::TIMESTAMP => scalar(row, i, Value::Timestamp),
+            Type::TIMESTAMP_ARRAY => array(row, i, Value::Timestamp),
+            Type::TIMESTAMPTZ => scalar(row, i, Value::TimestampTz),
+            Type::TIMESTAMPTZ_ARRAY => array(row, i, Value::TimestampTz),
+            Type::DATE => scalar(row, i, Value::Date),
+            Type::DATE_ARRAY => array(row, i, Value::Date),
+            Type::TIME => scalar(row, i, Value::Time),
+            Type::TIME_ARRAY => array(row, i, Value::Time),
+            Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::TEXT => scalar(row, i, Value::Text),
+            Type::VARCHAR_ARRAY | Type::BPCHAR_ARRAY | Type::NAME_ARRAY | Type::TEXT_ARRAY => array(row, i, Value::Text),
+            Type::BYTEA => scalar(row, i, |b: &[u8]| Value::Bytes(b.to_vec())),
+            Type::BYTEA_ARRAY => array(row, i, |b: &[u8]| Value::Bytes(b.to_vec())),
+            // Other types are excluded or cast to text by query::check_column_types, see type_supported
+            ref col_type => return Err(anyhow::anyhow!(
+                "Values of type {} cannot be decoded. Cast the column to text, or use --unsupported-types cast or exclude",
+                col_type)),
+        };
+        Ok(val?)
+    }
+
+    /// Feed the identity of the value to out: a tag for its kind, then its contents, with lengths
+    /// where they vary, so that different values never produce the same bytes.
+    /// Equal values (and fingerprints) are defined by it.
+    pub fn encode(&self, out: &mut dyn FnMut(&[u8])) {
+        let mut tagged = |tag: u8, bytes: &[u8]| {
+            out(&[tag]);
+            out(&(bytes.len() as u64).to_le_bytes());
+            out(bytes);
+        };
+        match self {
+            Value::Null => tagged(0, &[]),
+            Value::Undecodable => tagged(1, &[]),
+            Value::Bool(b) => tagged(2, &[*b as u8]),
+            Value::Int(n) => tagged(3, &n.to_le_bytes()),
+            Value::Float4(f) => tagged(4, &canonical_f32(*f).to_bits().to_le_bytes()),
+            Value::Float8(f) => tagged(5, &canonical_f64(*f).to_bits().to_le_bytes()),
+            Value::Numeric(s) => tagged(6, s.as_bytes()),
+            Value::Text(s) => tagged(7, s.as_bytes()),
+            Value::Bytes(b) => tagged(8, b),
+            Value::Timestamp(dt) => tagged(9, &dt.and_utc().timestamp_micros().to_le_bytes()),
+            Value::TimestampTz(dt) => tagged(10, &dt.timestamp_micros().to_le_bytes()),
+            Value::Date(d) => tagged(11, &d.num_days_from_ce().to_le_bytes()),
+            Value::Time(t) => {
+                let ns = t.num_seconds_from_midnight() as u64 * 1_000_000_000 + t.nanosecond() as u64;
+                tagged(12, &ns.to_le_bytes())
+            },
+            Value::Json(j) => tagged(13, j.to_string().as_bytes()),
+            Value::Uuid(u) => tagged(14, u.as_bytes()),
+            Value::Network(s) => tagged(15, s.as_bytes()),
+            Value::Geometric(s) => tagged(16, s.as_bytes()),
+            Value::Bits(s) => tagged(18, s.as_bytes()),
+            Value::Array(elements) => {
+                tagged(17, &(elements.len() as u64).to_le_bytes());
+                for e in elements {
+                    e.encode(out);
+                }
+            },
+        }
+    }
+
+    fn encoded(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        self.encode(&mut |b| bytes.extend_from_slice(b));
+        bytes
+    }
+
+    /// An estimate of the memory the value takes, to decide when to spill
+    pub fn memory(&self) -> usize {
+        std::mem::size_of::<Value>() + match self {
+            Value::Numeric(s) | Value::Text(s) | Value::Network(s) | Value::Geometric(s) | Value::Bits(s) => s.capacity(),
+            Value::Bytes(b) => b.capacity(),
+            Value::Json(j) => j.to_string().len(),
+            Value::Array(elements) => elements.iter().map(Value::memory).sum(),
+            _ => 0,
+        }
+    }
+
+    /// Like the value itself, but quoted and cast when it would not be a valid SQL literal as is
+    pub fn as_sql_literal(&self, col_type: &Type) -> String {
+        match *col_type {
+            Type::BOOL | Type::INT2 | Type::INT4 | Type::INT8 | Type::OID |
+            Type::VARCHAR | Type::BPCHAR | Type::BYTEA | Type::NAME | Type::TEXT => self.to_string(),
+            _ => match self {
+                Value::Null | Value::Undecodable => self.to_string(),
+                // A cast to bit would cut the string to one bit
+                Value::Bits(s) => format!("B'{}'", s),
+                _ => format!("{}::{}", quote(&self.input_text()), col_type.name()),
+            },
+        }
+    }
+
+    /// The text Postgres reads the value from: unquoted, with arrays in the {"a","b"} syntax
+    fn input_text(&self) -> String {
+        match self {
+            Value::Text(s) => s.clone(),
+            Value::Bytes(_) => self.to_string().trim_matches('\'').to_string(),
+            Value::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(|e| match e {
+                    Value::Null => String::from("NULL"),
+                    _ => format!("\"{}\"", e.input_text().replace('\\', "\\\\").replace('"', "\\\"")),
+                }).collect();
+                format!("{{{}}}", elements.join(","))
+            },
+            _ => self.to_string(),
+        }
+    }
+
+    /// The value as a typed JSON value. Numbers, booleans, json and text become their JSON counterparts,
+    /// numeric stays a string to keep it exact, bytea becomes base64, other values keep their text.
+    pub fn as_json(&self) -> serde_json::Value {
+        let number = |f: f64| serde_json::Number::from_f64(f).map(serde_json::Value::Number);
+        let typed = match self {
+            Value::Null => Some(serde_json::Value::Null),
+            Value::Bool(b) => Some(serde_json::Value::from(*b)),
+            Value::Int(n) => Some(serde_json::Value::from(*n)),
+            Value::Float4(f) => number(float4_as_f64(*f)),
+            Value::Float8(f) => number(*f),
+            Value::Text(s) => Some(serde_json::Value::from(s.as_str())),
+            Value::Bytes(b) => Some(serde_json::Value::from(BASE64_STANDARD.encode(b))),
+            Value::Json(j) => Some(j.clone()),
+            _ => None,
+        };
+        typed.unwrap_or_else(|| serde_json::Value::from(self.to_string()))
+    }
+
+    /// Compare values in ORDER BY order (nulls last). Text is compared bytewise.
+    pub fn sort_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float4(a), Value::Float4(b)) => OrderedFloat(*a).cmp(&OrderedFloat(*b)),
+            (Value::Float8(a), Value::Float8(b)) => OrderedFloat(*a).cmp(&OrderedFloat(*b)),
+            (Value::Numeric(a), Value::Numeric(b)) => numeric::cmp_text(a, b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.cmp(b),
+            (Value::TimestampTz(a), Value::TimestampTz(b)) => a.cmp(b),
+            (Value::Date(a), Value::Date(b)) => a.cmp(b),
+            (Value::Time(a), Value::Time(b)) => a.cmp(b),
+            _ => match self.sort_rank().cmp(&other.sort_rank()) {
+                Ordering::Equal => self.to_string().cmp(&other.to_string()),
+                ord => ord,
+            },
+        }
+    }
+
+    /// Like sort_cmp, but values are only equal when they are the same value (1.50 and 1.5 are not),
+    /// to pair rows by key in this order
+    pub fn pair_cmp(&self, other: &Value) -> Ordering {
+        self.sort_cmp(other).then_with(|| self.encoded().cmp(&other.encoded()))
+    }
+
+    fn sort_rank(&self) -> u8 {
+        match self {
+            Value::Undecodable => 1,
+            Value::Null => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// -0 equals 0, and all NaNs are equal, like in Postgres
+fn canonical_f32(f: f32) -> f32 {
+    if f == 0.0 { 0.0 } else if f.is_nan() { f32::NAN } else { f }
+}
+
+fn canonical_f64(f: f64) -> f64 {
+    if f == 0.0 { 0.0 } else if f.is_nan() { f64::NAN } else { f }
+}
+
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.encoded() == other.encoded()
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.encode(&mut |bytes| state.write(bytes));
+    }
+}
+
+/// The text of a value in reports: text is quoted, bytea is in the hex format, arrays are [ a, b ]
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "{}", NULL),
+            Value::Undecodable => write!(f, "{}", UNDECODABLE),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float4(x) => write!(f, "{}", x),
+            Value::Float8(x) => write!(f, "{}", x),
+            Value::Numeric(s) | Value::Network(s) | Value::Geometric(s) | Value::Bits(s) => write!(f, "{}", s),
+            Value::Text(s) => write!(f, "{}", quote(s)),
+            Value::Bytes(b) => {
+                write!(f, "'\\x")?;
+                for byte in b {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            },
+            Value::Timestamp(dt) => write!(f, "{}", dt),
+            Value::TimestampTz(dt) => write!(f, "{}", dt),
+            Value::Date(d) => write!(f, "{}", d),
+            Value::Time(t) => write!(f, "{}", t),
+            Value::Json(j) => write!(f, "{}", j),
+            Value::Uuid(u) => write!(f, "{}", u),
+            Value::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(Value::to_string).collect();
+                write!(f, "[ {} ]", elements.join(", "))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").unwrap()
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::Null.to_string(), "Null");
+        assert_eq!(Value::Text(String::from("it's")).to_string(), "'it''s'");
+        assert_eq!(Value::Bytes(vec![0, 255, 16]).to_string(), "'\\x00ff10'");
+        assert_eq!(Value::Float4(0.1).to_string(), "0.1");
+        assert_eq!(Value::Timestamp(timestamp("2020-01-02 03:04:05.5")).to_string(), "2020-01-02 03:04:05.500");
+        assert_eq!(Value::Array(vec![Value::Int(1), Value::Null]).to_string(), "[ 1, Null ]");
+        assert_eq!(bits(BitVec::from_bytes(&[0b1000_0001])).to_string(), "10000001");
+    }
+
+    #[test]
+    fn identity() {
+        assert_eq!(Value::Float8(f64::NAN), Value::Float8(f64::NAN));
+        assert_eq!(Value::Float8(-0.0), Value::Float8(0.0));
+        assert_ne!(Value::Int(1), Value::Numeric(String::from("1")));
+        assert_ne!(Value::Text(String::from("1")), Value::Int(1));
+        // Lengths are part of the encoding, so array elements cannot run into each other
+        let ab_c = Value::Array(vec![Value::Text(String::from("ab")), Value::Text(String::from("c"))]);
+        let a_bc = Value::Array(vec![Value::Text(String::from("a")), Value::Text(String::from("bc"))]);
+        assert_ne!(ab_c, a_bc);
+        assert_ne!(Value::Array(vec![Value::Null]), Value::Array(vec![]));
+        // All bits count, not just whether any is set
+        assert_ne!(Value::Bits(String::from("00000001")), Value::Bits(String::from("10000000")));
+        assert_ne!(Value::Bits(String::from("01")), Value::Bits(String::from("010")));
+        assert_ne!(Value::Bits(String::from("1")), Value::Bool(true));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(Value::Int(3).as_sql_literal(&Type::INT4), "3");
+        assert_eq!(Value::Text(String::from("o'k")).as_sql_literal(&Type::TEXT), "'o''k'");
+        assert_eq!(Value::Bytes(vec![1, 2]).as_sql_literal(&Type::BYTEA), "'\\x0102'");
+        assert_eq!(Value::Null.as_sql_literal(&Type::DATE), "Null");
+        let date = Value::Date(NaiveDate::from_ymd_opt(2020, 2, 29).unwrap());
+        assert_eq!(date.as_sql_literal(&Type::DATE), "'2020-02-29'::date");
+        let json = Value::Json(serde_json::json!({"a": "it's"}));
+        assert_eq!(json.as_sql_literal(&Type::JSONB), "'{\"a\":\"it''s\"}'::jsonb");
+        assert_eq!(Value::Bits(String::from("0101")).as_sql_literal(&Type::VARBIT), "B'0101'");
+        let point = Value::Geometric(point_as_str(geo_types::Point::new(1.5, -2.0)));
+        assert_eq!(point.as_sql_literal(&Type::POINT), "'(1.5,-2)'::point");
+        let rect = geo_types::Rect::new(geo_types::coord! { x: 3.0, y: 2.0 }, geo_types::coord! { x: 1.0, y: 4.0 });
+        assert_eq!(Value::Geometric(rect_as_str(rect)).as_sql_literal(&Type::BOX), "'(3,4),(1,2)'::box");
+        let ints = Value::Array(vec![Value::Int(1), Value::Null]);
+        assert_eq!(ints.as_sql_literal(&Type::INT4_ARRAY), "'{\"1\",NULL}'::_int4");
+    }
+
+    #[test]
+    fn undecodable_literals() {
+        use crate::pg_hasher::{row_as_delete, row_as_insert, row_as_update, Record};
+        use crate::pg_hasher::test_support::record;
+        let columns = [("id", Type::INT4), ("name", Type::TEXT), ("born", Type::DATE)];
+        let row = record(&columns, vec![Value::Int(7), Value::Text(String::from("a")), Value::Undecodable]);
+        let key = [String::from("id")];
+        // The statements that use the undecodable value are left out, the others are not
+        assert!(row_as_insert("people", &row).starts_with("-- Warning: "));
+        assert!(row_as_update("people", &key, &row, &["born"]).starts_with("-- Warning: "));
+        assert_eq!(row_as_update("people", &key, &row, &["name"]), "update \"people\" set \"name\" = 'a' where \"id\" = 7;");
+        assert_eq!(row_as_delete("people", &key, &row), "delete from \"people\" where \"id\" = 7;");
+        let undecodable_key = Record { values: vec![Value::Undecodable, Value::Null, Value::Null], ..row };
+        assert!(row_as_delete("people", &key, &undecodable_key).starts_with("-- Warning: "));
+    }
+
+    /// Split an array literal back into its elements, the way Postgres reads it
+    fn parse_array_literal(literal: &str) -> Vec<Option<String>> {
+        let quoted = &literal[..literal.rfind("::").unwrap()];
+        let text = quoted[1..quoted.len() - 1].replace("''", "'");
+        let mut chars = text.strip_prefix('{').unwrap().strip_suffix('}').unwrap().chars();
+        let mut elements = Vec::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    let mut element = String::new();
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => element.push(chars.next().unwrap()),
+                            '"' => break,
+                            _ => element.push(c),
+                        }
+                    }
+                    elements.push(Some(element));
+                },
+                'N' => {
+                    assert_eq!(chars.by_ref().take(3).collect::<String>(), "ULL");
+                    elements.push(None);
+                },
+                _ => assert_eq!(c, ','),
+            }
+        }
+        elements
+    }
+
+    #[test]
+    fn array_literals_parse_back() {
+        let elements = vec![
+            Value::Text(String::from("a \"quoted\", back\\slashed, it's {x}")),
+            Value::Text(String::from("NULL")),
+            Value::Text(String::new()),
+            Value::Null,
+        ];
+        let literal = Value::Array(elements).as_sql_literal(&Type::TEXT_ARRAY);
+        assert_eq!(parse_array_literal(&literal), vec![
+            Some(String::from("a \"quoted\", back\\slashed, it's {x}")),
+            Some(String::from("NULL")),
+            Some(String::new()),
+            None,
+        ]);
+        let literal = Value::Array(vec![Value::Bytes(vec![1, 255])]).as_sql_literal(&Type::BYTEA_ARRAY);
+        assert_eq!(parse_array_literal(&literal), vec![Some(String::from("\\x01ff"))]);
+        let literal = Value::Array(vec![Value::Json(serde_json::json!({"a": "b"}))]).as_sql_literal(&Type::JSONB_ARRAY);
+        assert_eq!(parse_array_literal(&literal), vec![Some(String::from("{\"a\":\"b\"}"))]);
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(Value::Int(7).as_json(), serde_json::json!(7));
+        assert_eq!(Value::Float4(0.1).as_json(), serde_json::json!(0.1));
+        assert_eq!(Value::Float8(f64::INFINITY).as_json(), serde_json::json!("inf"));
+        assert_eq!(Value::Numeric(String::from("1.50")).as_json(), serde_json::json!("1.50"));
+        assert_eq!(Value::Text(String::from("it's")).as_json(), serde_json::json!("it's"));
+        assert_eq!(Value::Bytes(vec![0, 255]).as_json(), serde_json::json!("AP8="));
+        assert_eq!(Value::Null.as_json(), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn sort_order() {
+        assert_eq!(Value::Int(2).sort_cmp(&Value::Int(10)), Ordering::Less);
+        assert_eq!(Value::Float8(f64::NAN).sort_cmp(&Value::Float8(1.0)), Ordering::Greater);
+        assert_eq!(Value::Text(String::from("B")).sort_cmp(&Value::Text(String::from("a"))), Ordering::Less);
+        assert_eq!(Value::Null.sort_cmp(&Value::Int(1)), Ordering::Greater);
+        assert_eq!(Value::Null.sort_cmp(&Value::Null), Ordering::Equal);
+    }
+
+    #[test]
+    fn spill_round_trip() {
+        let values = vec![
+            Value::Float8(f64::NAN), Value::Float4(-0.5), Value::Bytes(vec![0, 1, 2]), Value::Bits(String::from("0101")),
+            Value::TimestampTz(timestamp("2020-01-02 03:04:05").and_utc()),
+            Value::Array(vec![Value::Json(serde_json::json!({"b": 1, "a": [true]})), Value::Null]),
+        ];
+        let line = serde_json::to_string(&values).unwrap();
+        let read: Vec<Value> = serde_json::from_str(&line).unwrap();
+        assert!(values == read);
+    }
+}
@@ -0,0 +1,133 @@
+use std::str::FromStr;
+use anyhow::Result;
+use tokio_postgres::{Client, Column};
+use crate::cli;
+use crate::pg_hasher;
+
+/// What to do with result columns of a type that pg_hasher cannot convert
+#[derive(Clone, Copy, PartialEq)]
+pub enum UnsupportedTypes {
+    /// Refuse to run the comparison
+    Error,
+    /// Have the server cast the column to text
+    Cast,
+    /// Leave the column out of the comparison
+    Exclude,
+}
+
+impl FromStr for UnsupportedTypes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<UnsupportedTypes> {
+        match s {
+            "error" => Ok(UnsupportedTypes::Error),
+            "cast" => Ok(UnsupportedTypes::Cast),
+            "exclude" => Ok(UnsupportedTypes::Exclude),
+            _ => Err(anyhow::anyhow!("Invalid unsupported types policy {}, use error, cast or exclude", s)),
+        }
+    }
+}
+
+/// Check the result columns of a query for types that pg_hasher cannot convert, and apply the policy.
+/// Returns the query to run instead (None when the query can run as is), and warnings for the report.
+pub fn check_column_types(query: &str, side: &str, columns: &[Column], key_columns: &[String],
+                          policy: UnsupportedTypes) -> Result<(Option<String>, Vec<String>)> {
+    let unsupported: Vec<&Column> = columns.iter()
+        .filter(|c| !pg_hasher::type_supported(c.type_()))
+        .collect();
+    if unsupported.is_empty() {
+        return Ok((None, Vec::new()));
+    }
+
+    let mut warnings: Vec<String> = Vec::new();
+    let mut select_list: Vec<String> = Vec::new();
+    for col in columns {
+        let name = pg_hasher::str_as_name(col.name());
+        if pg_hasher::type_supported(col.type_()) {
+            select_list.push(name);
+            continue;
+        }
+        match policy {
+            UnsupportedTypes::Error => {
+                let cols: Vec<String> = unsupported.iter()
+                    .map(|c| format!("{} ({})", c.name(), c.type_()))
+                    .collect();
+                return Err(anyhow::anyhow!(
+                    "The {} query returns columns of unsupported types: {}. Cast them to text, or use --unsupported-types cast or exclude",
+                    side, cols.join(", ")));
+            },
+            UnsupportedTypes::Cast => {
+                warnings.push(format!("column {} of unsupported type {} is compared as text on the {} side",
+                                      col.name(), col.type_(), side));
+                select_list.push(format!("{}::text as {}", name, name));
+            },
+            UnsupportedTypes::Exclude => {
+                if key_columns.iter().any(|k| k == col.name()) {
+                    return Err(anyhow::anyhow!("Key column {} of unsupported type {} cannot be excluded",
+                                               col.name(), col.type_()));
+                }
+                warnings.push(format!("column {} of unsupported type {} is excluded from the comparison on the {} side",
+                                      col.name(), col.type_(), side));
+            },
+        }
+    }
+    let query = query.trim_end().trim_end_matches(';');
+    Ok((Some(format!("select {} from ({}) as dbdiff_query", select_list.join(", "), query)), warnings))
+}
+
+/// Build the query for a (possibly schema qualified) table, with the columns, where clause and
+/// ordering from the command line. Column names are read from the catalog, so that excluded columns
+/// can be left out and text key columns can be sorted bytewise for a merge join.
+pub async fn build_table_query(client: &Client, table_name: &str, args: &cli::Params) -> Result<String> {
+    let table = pg_hasher::table_as_name(table_name);
+    let catalog_columns = client.query(
+        "select a.attname::text, t.typcollation <> 0 \
+         from pg_catalog.pg_attribute a join pg_catalog.pg_type t on t.oid = a.atttypid \
+         where a.attrelid = to_regclass($1) and a.attnum > 0 and not a.attisdropped \
+         order by a.attnum",
+        &[&table],
+    ).await?;
+    if catalog_columns.is_empty() {
+        return Err(anyhow::anyhow!("Table {} does not exist or has no columns", table));
+    }
+    let table_columns: Vec<(String, bool)> = catalog_columns.iter()
+        .map(|r| (r.get::<usize, String>(0), r.get::<usize, bool>(1)))
+        .collect();
+
+    for col in args.columns.iter().chain(args.exclude_columns.iter()).chain(args.key_columns.iter()) {
+        if !table_columns.iter().any(|(name, _collatable)| name == col) {
+            return Err(anyhow::anyhow!("Column {} does not exist in table {}", col, table));
+        }
+    }
+    if let Some(key_col) = args.key_columns.iter().find(|k| args.exclude_columns.contains(k)) {
+        return Err(anyhow::anyhow!("Key column {} cannot be excluded", key_col));
+    }
+
+    let mut select_list: Vec<String> = Vec::new();
+    for (name, _collatable) in &table_columns {
+        // Key columns are always selected, so that rows can be paired
+        let selected = args.key_columns.contains(name) ||
+            (args.columns.is_empty() || args.columns.contains(name)) && !args.exclude_columns.contains(name);
+        if selected {
+            select_list.push(pg_hasher::str_as_name(name));
+        }
+    }
+    let mut query = format!("select {} from {}", select_list.join(", "), table);
+    if !args.where_clause.is_empty() {
+        query = format!("{} where ({})", query, args.where_clause);
+    }
+    if args.sorted {
+        let mut order_by: Vec<String> = Vec::new();
+        for key_col in &args.key_columns {
+            let collatable = table_columns.iter().any(|(name, collatable)| name == key_col && *collatable);
+            if collatable {
+                // The merge join compares text bytewise
+                order_by.push(format!("{} collate \"C\"", pg_hasher::str_as_name(key_col)));
+            } else {
+                order_by.push(pg_hasher::str_as_name(key_col));
+            }
+        }
+        query = format!("{} order by {}", query, order_by.join(", "));
+    }
+    Ok(query)
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+use tokio_postgres::Client;
+use crate::exit::ErrorKind;
+use crate::pg_hasher::str_as_name;
+
+/// The comparable part of a database object
+#[derive(PartialEq)]
+enum Definition {
+    Table,
+    Column { data_type: String, not_null: bool, default: String },
+    Sequence(String),
+    Constraint(String),
+    Index(String),
+    Trigger(String),
+    View(String),
+}
+
+impl std::fmt::Display for Definition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Definition::Table => write!(f, "table"),
+            Definition::Column { data_type, not_null, default } => {
+                write!(f, "{}", data_type)?;
+                if *not_null {
+                    write!(f, " not null")?;
+                }
+                if !default.is_empty() {
+                    write!(f, " default {}", default)?;
+                }
+                Ok(())
+            },
+            Definition::Sequence(def) | Definition::Constraint(def) | Definition::Index(def) |
+            Definition::Trigger(def) | Definition::View(def) => write!(f, "{}", def),
+        }
+    }
+}
+
+/// Objects are identified by their kind, their table (empty for objects that don't belong to a table)
+/// and their name
+type ObjectKey = (&'static str, String, String);
+
+fn object_name(key: &ObjectKey) -> String {
+    let (kind, table, name) = key;
+    if table.is_empty() {
+        format!("{} {}", kind, name)
+    } else {
+        format!("{} {}.{}", kind, table, name)
+    }
+}
+
+/// Read the objects in a schema from the catalog.
+/// The search_path is set to the schema, so that definitions don't contain the schema name
+/// and schemas with different names can be compared.
+async fn read_schema(client: &Client, schema: &str) -> Result<BTreeMap<ObjectKey, Definition>> {
+    client.query("select pg_catalog.set_config('search_path', $1, false)", &[&str_as_name(schema)]).await?;
+    let mut objects: BTreeMap<ObjectKey, Definition> = BTreeMap::new();
+    for row in client.query(
+        "select c.relname::text from pg_catalog.pg_class c \
+         join pg_catalog.pg_namespace n on n.oid = c.relnamespace \
+         where n.nspname = $1 and c.relkind in ('r', 'p')",
+        &[&schema]).await? {
+        objects.insert(("table", String::new(), row.get(0)), Definition::Table);
+    }
+    for row in client.query(
+        "select c.relname::text, a.attname::text, pg_catalog.format_type(a.atttypid, a.atttypmod), \
+         a.attnotnull, coalesce(pg_catalog.pg_get_expr(d.adbin, d.adrelid), '') \
+         from pg_catalog.pg_attribute a \
+         join pg_catalog.pg_class c on c.oid = a.attrelid \
+         join pg_catalog.pg_namespace n on n.oid = c.relnamespace \
+         left join pg_catalog.pg_attrdef d on d.adrelid = a.attrelid and d.adnum = a.attnum \
+         where n.nspname = $1 and c.relkind in ('r', 'p') and a.attnum > 0 and not a.attisdropped",
+        &[&schema]).await? {
+        objects.insert(("column", row.get(0), row.get(1)),
+                       Definition::Column { data_type: row.get(2), not_null: row.get(3), default: row.get(4) });
+    }
+    for row in client.query(
+        "select sequencename::text, format('as %s increment by %s minvalue %s maxvalue %s start with %s %s', \
+         data_type, increment_by, min_value, max_value, start_value, case when cycle then 'cycle' else 'no cycle' end) \
+         from pg_catalog.pg_sequences where schemaname = $1",
+        &[&schema]).await? {
+        objects.insert(("sequence", String::new(), row.get(0)), Definition::Sequence(row.get(1)));
+    }
+    for row in client.query(
+        "select c.relname::text, con.conname::text, pg_catalog.pg_get_constraintdef(con.oid) \
+         from pg_catalog.pg_constraint con \
+         join pg_catalog.pg_class c on c.oid = con.conrelid \
+         join pg_catalog.pg_namespace n on n.oid = c.relnamespace \
+         where n.nspname = $1",
+        &[&schema]).await? {
+        objects.insert(("constraint", row.get(0), row.get(1)), Definition::Constraint(row.get(2)));
+    }
+    // Indexes that back a constraint are created along with the constraint.
+    // Index and trigger definitions always qualify the table, so the schema is removed from those.
+    for row in client.query(
+        "select t.relname::text, i.relname::text, \
+         replace(pg_catalog.pg_get_indexdef(i.oid), ' ' || quote_ident(n.nspname) || '.', ' ') \
+         from pg_catalog.pg_index x \
+         join pg_catalog.pg_class i on i.oid = x.indexrelid \
+         join pg_catalog.pg_class t on t.oid = x.indrelid \
+         join pg_catalog.pg_namespace n on n.oid = t.relnamespace \
+         where n.nspname = $1 and t.relkind in ('r', 'p') \
+         and not exists (select 1 from pg_catalog.pg_constraint con where con.conindid = x.indexrelid)",
+        &[&schema]).await? {
+        objects.insert(("index", row.get(0), row.get(1)), Definition::Index(row.get(2)));
+    }
+    for row in client.query(
+        "select c.relname::text, tg.tgname::text, \
+         replace(pg_catalog.pg_get_triggerdef(tg.oid), ' ' || quote_ident(n.nspname) || '.', ' ') \
+         from pg_catalog.pg_trigger tg \
+         join pg_catalog.pg_class c on c.oid = tg.tgrelid \
+         join pg_catalog.pg_namespace n on n.oid = c.relnamespace \
+         where n.nspname = $1 and not tg.tgisinternal",
+        &[&schema]).await? {
+        objects.insert(("trigger", row.get(0), row.get(1)), Definition::Trigger(row.get(2)));
+    }
+    for row in client.query(
+        "select viewname::text, definition from pg_catalog.pg_views where schemaname = $1",
+        &[&schema]).await? {
+        objects.insert(("view", String::new(), row.get(0)), Definition::View(row.get(1)));
+    }
+    Ok(objects)
+}
+
+/// A difference between the source and the destination schema
+pub enum SchemaDiff {
+    OnlyOnSource(String, String),
+    OnlyOnDest(String, String),
+    Changed(String, String, String),
+}
+
+/// The statements that make the destination schema equal to the source schema.
+/// Each statement comes with its phase, so that dependent objects are dropped first and created last:
+/// 0-2 drop constraints, indexes, triggers and views, then columns and tables, then sequences,
+/// 3-6 create sequences, then tables, then columns, then constraints, indexes, triggers and views.
+struct AlterScript {
+    statements: Vec<(u8, String)>,
+}
+
+impl AlterScript {
+    fn create(&mut self, key: &ObjectKey, def: &Definition) {
+        let (_kind, table, name) = key;
+        let statement = match def {
+            Definition::Table => (4, format!("create table {} ();", str_as_name(name))),
+            Definition::Column { data_type, not_null, default } => {
+                let mut col = format!("{} {}", str_as_name(name), data_type);
+                if *not_null {
+                    col.push_str(" not null");
+                }
+                if !default.is_empty() {
+                    col.push_str(&format!(" default {}", default));
+                }
+                (5, format!("alter table {} add column {};", str_as_name(table), col))
+            },
+            Definition::Sequence(def) => (3, format!("create sequence {} {};", str_as_name(name), def)),
+            Definition::Constraint(def) =>
+                (6, format!("alter table {} add constraint {} {};", str_as_name(table), str_as_name(name), def)),
+            Definition::Index(def) | Definition::Trigger(def) => (6, format!("{};", def)),
+            Definition::View(def) => (6, format!("create view {} as {}", str_as_name(name), def)),
+        };
+        self.statements.push(statement);
+    }
+
+    fn drop(&mut self, key: &ObjectKey, def: &Definition) {
+        let (_kind, table, name) = key;
+        let statement = match def {
+            Definition::Table => (1, format!("drop table {};", str_as_name(name))),
+            Definition::Column { .. } =>
+                (1, format!("alter table {} drop column {};", str_as_name(table), str_as_name(name))),
+            Definition::Sequence(_) => (2, format!("drop sequence {};", str_as_name(name))),
+            Definition::Constraint(_) =>
+                (0, format!("alter table {} drop constraint {};", str_as_name(table), str_as_name(name))),
+            Definition::Index(_) => (0, format!("drop index {};", str_as_name(name))),
+            Definition::Trigger(_) => (0, format!("drop trigger {} on {};", str_as_name(name), str_as_name(table))),
+            Definition::View(_) => (0, format!("drop view {};", str_as_name(name))),
+        };
+        self.statements.push(statement);
+    }
+
+    fn alter(&mut self, key: &ObjectKey, source: &Definition, dest: &Definition) {
+        let (_kind, table, name) = key;
+        match (source, dest) {
+            (Definition::Column { data_type, not_null, default },
+             Definition::Column { data_type: dest_type, not_null: dest_not_null, default: dest_default }) => {
+                let alter_column = format!("alter table {} alter column {}", str_as_name(table), str_as_name(name));
+                if data_type != dest_type {
+                    self.statements.push((5, format!("{} type {};", alter_column, data_type)));
+                }
+                if not_null != dest_not_null {
+                    let action = if *not_null { "set" } else { "drop" };
+                    self.statements.push((5, format!("{} {} not null;", alter_column, action)));
+                }
+                if default != dest_default {
+                    if default.is_empty() {
+                        self.statements.push((5, format!("{} drop default;", alter_column)));
+                    } else {
+                        self.statements.push((5, format!("{} set default {};", alter_column, default)));
+                    }
+                }
+            },
+            (Definition::Sequence(def), _) =>
+                self.statements.push((3, format!("alter sequence {} {};", str_as_name(name), def))),
+            _ => {
+                self.drop(key, dest);
+                self.create(key, source);
+            },
+        }
+    }
+}
+
+/// The differences between the objects of two schemas, and the statements that make the destination equal to the source
+fn diff_objects(source_objects: &BTreeMap<ObjectKey, Definition>,
+                dest_objects: &BTreeMap<ObjectKey, Definition>) -> (Vec<SchemaDiff>, Vec<String>) {
+    let mut diffs: Vec<SchemaDiff> = Vec::new();
+    let mut script = AlterScript { statements: Vec::new() };
+
+    // Everything that belongs to a table is only reported when the table itself exists on both sides
+    let has_table = |objects: &BTreeMap<ObjectKey, Definition>, table: &String| {
+        table.is_empty() || objects.contains_key(&("table", String::new(), table.clone()))
+    };
+    for (key, def) in source_objects {
+        match dest_objects.get(key) {
+            None => {
+                if has_table(dest_objects, &key.1) {
+                    diffs.push(SchemaDiff::OnlyOnSource(object_name(key), def.to_string()));
+                }
+                script.create(key, def);
+            },
+            Some(dest_def) if dest_def != def => {
+                diffs.push(SchemaDiff::Changed(object_name(key), def.to_string(), dest_def.to_string()));
+                script.alter(key, def, dest_def);
+            },
+            Some(_) => (),
+        }
+    }
+    for (key, def) in dest_objects {
+        if source_objects.contains_key(key) {
+            continue;
+        }
+        if has_table(source_objects, &key.1) {
+            diffs.push(SchemaDiff::OnlyOnDest(object_name(key), def.to_string()));
+            script.drop(key, def);
+        }
+    }
+
+    script.statements.sort_by_key(|(phase, _statement)| *phase);
+    (diffs, script.statements.into_iter().map(|(_phase, statement)| statement).collect())
+}
+
+/// Compare the schema of the source with the schema of the destination.
+/// Returns the differences, and the statements that make the destination equal to the source.
+pub async fn compare_schemas(source: &Client, dest: &Client, source_schema: &str,
+                             dest_schema: &str) -> Result<(Vec<SchemaDiff>, Vec<String>)> {
+    let source_objects = read_schema(source, source_schema).await.context(ErrorKind::Query)?;
+    let dest_objects = read_schema(dest, dest_schema).await.context(ErrorKind::Query)?;
+    let (diffs, script) = diff_objects(&source_objects, &dest_objects);
+    let mut statements: Vec<String> = vec![format!("set local search_path = {};", str_as_name(dest_schema))];
+    statements.extend(script);
+    Ok((diffs, statements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(data_type: &str) -> Definition {
+        Definition::Column { data_type: String::from(data_type), not_null: false, default: String::new() }
+    }
+
+    fn key(kind: &'static str, table: &str, name: &str) -> ObjectKey {
+        (kind, String::from(table), String::from(name))
+    }
+
+    fn names(diffs: &[SchemaDiff]) -> Vec<String> {
+        diffs.iter().map(|diff| match diff {
+            SchemaDiff::OnlyOnSource(name, _def) => format!("< {}", name),
+            SchemaDiff::OnlyOnDest(name, _def) => format!("> {}", name),
+            SchemaDiff::Changed(name, _source, _dest) => format!("~ {}", name),
+        }).collect()
+    }
+
+    #[test]
+    fn phases() {
+        let mut source = BTreeMap::new();
+        let mut dest = BTreeMap::new();
+        for objects in [&mut source, &mut dest] {
+            objects.insert(key("table", "", "t"), Definition::Table);
+        }
+        source.insert(key("column", "t", "a"), column("bigint"));
+        dest.insert(key("column", "t", "a"), column("integer"));
+        source.insert(key("sequence", "", "s"), Definition::Sequence(String::from("as bigint")));
+        source.insert(key("view", "", "v"), Definition::View(String::from(" SELECT a FROM t;")));
+        dest.insert(key("column", "t", "b"), column("text"));
+        dest.insert(key("constraint", "t", "c"), Definition::Constraint(String::from("CHECK (b <> '')")));
+        let (diffs, statements) = diff_objects(&source, &dest);
+        assert_eq!(names(&diffs), vec!["~ column t.a", "< sequence s", "< view v", "> column t.b", "> constraint t.c"]);
+        // Dependent objects are dropped first and created last
+        assert_eq!(statements, vec![
+            "alter table \"t\" drop constraint \"c\";",
+            "alter table \"t\" drop column \"b\";",
+            "create sequence \"s\" as bigint;",
+            "alter table \"t\" alter column \"a\" type bigint;",
+            "create view \"v\" as  SELECT a FROM t;",
+        ]);
+    }
+
+    #[test]
+    fn missing_tables() {
+        let mut source = BTreeMap::new();
+        let mut dest = BTreeMap::new();
+        source.insert(key("table", "", "new"), Definition::Table);
+        source.insert(key("column", "new", "id"),
+                      Definition::Column { data_type: String::from("integer"), not_null: true, default: String::new() });
+        source.insert(key("column", "new", "name"), column("text"));
+        dest.insert(key("table", "", "old"), Definition::Table);
+        dest.insert(key("column", "old", "id"), column("integer"));
+        dest.insert(key("index", "old", "old_id"), Definition::Index(String::from("CREATE INDEX old_id ON old USING btree (id)")));
+        let (diffs, statements) = diff_objects(&source, &dest);
+        // Only the tables are reported, not what belongs to them
+        assert_eq!(names(&diffs), vec!["< table new", "> table old"]);
+        assert_eq!(statements, vec![
+            "drop table \"old\";",
+            "create table \"new\" ();",
+            "alter table \"new\" add column \"id\" integer not null;",
+            "alter table \"new\" add column \"name\" text;",
+        ]);
+    }
+}
@@ -0,0 +1,499 @@
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{Context, Result};
+use crate::differ::{self, KeyedRecord, RowDiff, UnmatchedRows};
+use crate::pg_hasher::{self, HashOptions, Record, RecordColumn, Value};
+
+/// Run files of chunks that are compared at the same time need their own name
+static RUN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of runs that are merged at the same time. Beyond this, all runs are merged into one run per side.
+const MAX_RUNS: usize = 64;
+
+/// A run file: unmatched rows of one side, ordered by fingerprint, one JSON line per distinct row
+/// with its fingerprint, its number of copies and its values. The file is removed when dropped.
+struct RunFile {
+    path: PathBuf,
+    columns: Arc<Vec<RecordColumn>>,
+}
+
+impl RunFile {
+    fn reader(&self) -> Result<RunReader> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("Could not read spill file {}", self.path.display()))?;
+        Ok(RunReader { path: self.path.clone(), columns: self.columns.clone(), lines: BufReader::new(file).lines() })
+    }
+}
+
+impl Drop for RunFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Writes a run file, rows have to be written in order of fingerprint
+struct RunWriter {
+    run: RunFile,
+    writer: BufWriter<File>,
+}
+
+impl RunWriter {
+    fn create(dir: &Path, side: &str, columns: Arc<Vec<RecordColumn>>) -> Result<RunWriter> {
+        let path = dir.join(format!(
+            "dbdiff-{}-{}-{}.jsonl", std::process::id(), RUN_FILES.fetch_add(1, Ordering::Relaxed), side));
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create spill file {}", path.display()))?;
+        Ok(RunWriter { run: RunFile { path, columns }, writer: BufWriter::new(file) })
+    }
+
+    fn write(&mut self, hash: u128, record: &Record, copies: usize) -> Result<()> {
+        let line = serde_json::json!({ "hash": format!("{:032x}", hash), "copies": copies, "values": record.values });
+        writeln!(self.writer, "{}", line)
+            .with_context(|| format!("Could not write spill file {}", self.run.path.display()))
+    }
+
+    fn finish(mut self) -> Result<RunFile> {
+        self.writer.flush().with_context(|| format!("Could not write spill file {}", self.run.path.display()))?;
+        Ok(self.run)
+    }
+}
+
+struct RunReader {
+    path: PathBuf,
+    columns: Arc<Vec<RecordColumn>>,
+    lines: Lines<BufReader<File>>,
+}
+
+impl RunReader {
+    /// The next row of the run: its fingerprint, record and number of copies
+    fn next_row(&mut self) -> Result<Option<(u128, Record, usize)>> {
+        let line = match self.lines.next() {
+            Some(line) => line.with_context(|| format!("Could not read spill file {}", self.path.display()))?,
+            None => return Ok(None),
+        };
+        let line: serde_json::Value = serde_json::from_str(&line)?;
+        let invalid = || anyhow::anyhow!("Invalid line in spill file {}", self.path.display());
+        let hash = u128::from_str_radix(line["hash"].as_str().ok_or_else(invalid)?, 16)?;
+        let copies = line["copies"].as_u64().ok_or_else(invalid)? as usize;
+        let values: Vec<Value> = serde_json::from_value(line["values"].clone())?;
+        Ok(Some((hash, Record { columns: self.columns.clone(), values }, copies)))
+    }
+}
+
+/// Unmatched rows that were moved to disk to keep memory use down, as sorted run files per side.
+/// At the end the runs are merged, and the rows that are still unmatched are handed on in order,
+/// or (with key columns) sorted by key in runs of at most max_rows rows or max_bytes bytes.
+pub struct SpillStore {
+    dir: PathBuf,
+    max_rows: usize,
+    max_bytes: usize,
+    source: Vec<RunFile>,
+    dest: Vec<RunFile>,
+}
+
+impl SpillStore {
+    /// A store in dir, that keeps at most max_rows rows (and max_bytes bytes if not 0) in memory when sorting by key
+    pub fn new(dir: PathBuf, max_rows: usize, max_bytes: usize) -> SpillStore {
+        SpillStore { dir, max_rows, max_bytes, source: Vec::new(), dest: Vec::new() }
+    }
+
+    /// Move the unmatched rows of both sides to a new run file per side
+    pub fn spill(&mut self, source: &mut UnmatchedRows, dest: &mut UnmatchedRows) -> Result<()> {
+        for (side, rows, runs) in [("source", source, &mut self.source), ("destination", dest, &mut self.dest)] {
+            let rows = rows.drain_sorted();
+            if let Some((_hash, record, _copies)) = rows.first() {
+                let mut writer = RunWriter::create(&self.dir, side, record.columns.clone())?;
+                for (hash, record, copies) in &rows {
+                    writer.write(*hash, record, *copies)?;
+                }
+                runs.push(writer.finish()?);
+            }
+        }
+        if self.source.len().max(self.dest.len()) > MAX_RUNS {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Merge all runs into a single run per side
+    fn compact(&mut self) -> Result<()> {
+        let mut source_writer = match self.source.first() {
+            Some(run) => Some(RunWriter::create(&self.dir, "source", run.columns.clone())?),
+            None => None,
+        };
+        let mut dest_writer = match self.dest.first() {
+            Some(run) => Some(RunWriter::create(&self.dir, "destination", run.columns.clone())?),
+            None => None,
+        };
+        self.merge(|is_source, hash, record, copies| {
+            let writer = if is_source { source_writer.as_mut() } else { dest_writer.as_mut() };
+            match writer {
+                Some(writer) => writer.write(hash, &record, copies),
+                None => Ok(()),
+            }
+        })?;
+        self.source = source_writer.map(|writer| writer.finish()).into_iter().collect::<Result<Vec<RunFile>>>()?;
+        self.dest = dest_writer.map(|writer| writer.finish()).into_iter().collect::<Result<Vec<RunFile>>>()?;
+        Ok(())
+    }
+
+    /// Merge the run files and the rows that are still in memory, and return the rows that are unmatched
+    /// in the end. They are all read back in memory, see finish_unkeyed and finish_keyed to avoid that.
+    pub fn finish(mut self, mut source: UnmatchedRows, mut dest: UnmatchedRows) -> Result<(UnmatchedRows, UnmatchedRows)> {
+        self.spill(&mut source, &mut dest)?;
+        self.merge(|is_source, hash, record, copies| {
+            if is_source {
+                source.add_record(hash, record, copies);
+            } else {
+                dest.add_record(hash, record, copies);
+            }
+            Ok(())
+        })?;
+        Ok((source, dest))
+    }
+
+    /// Merge the run files and the rows that are still in memory, and emit the rows that are unmatched
+    /// in the end as they are found, with whether they are on the source and their number of copies
+    pub fn finish_unkeyed(mut self, mut source: UnmatchedRows, mut dest: UnmatchedRows,
+                          mut emit: impl FnMut(bool, Record, usize) -> Result<()>) -> Result<()> {
+        self.spill(&mut source, &mut dest)?;
+        self.merge(|is_source, _hash, record, copies| emit(is_source, record, copies))
+    }
+
+    /// Merge the run files and the rows that are still in memory, sort the rows that are unmatched
+    /// in the end by key, and pair them by key (see differ::pair_by_key) to emit their differences
+    pub fn finish_keyed(mut self, mut source: UnmatchedRows, mut dest: UnmatchedRows, key_columns: &[String],
+                        options: &HashOptions, emit: impl FnMut(RowDiff) -> Result<()>) -> Result<()> {
+        self.spill(&mut source, &mut dest)?;
+        let mut source_sorter = KeySorter::new(&self, "source", key_columns, options);
+        let mut dest_sorter = KeySorter::new(&self, "destination", key_columns, options);
+        self.merge(|is_source, hash, record, copies| {
+            if is_source {
+                source_sorter.add(hash, record, copies)
+            } else {
+                dest_sorter.add(hash, record, copies)
+            }
+        })?;
+        self.source.clear();
+        self.dest.clear();
+        differ::pair_by_key(key_columns, options, source_sorter.into_rows()?, dest_sorter.into_rows()?, emit)
+    }
+
+    /// Merge the runs of both sides in order of fingerprint, and emit the rows that are unmatched,
+    /// with whether they are on the source. All copies of a row on one side cancel out against
+    /// the copies on the other side.
+    fn merge(&self, mut emit: impl FnMut(bool, u128, Record, usize) -> Result<()>) -> Result<()> {
+        // Every reader is a run of the source or the destination, read in order of fingerprint
+        let mut readers: Vec<(bool, RunReader)> = Vec::new();
+        for run in &self.source {
+            readers.push((true, run.reader()?));
+        }
+        for run in &self.dest {
+            readers.push((false, run.reader()?));
+        }
+        let mut heads: Vec<Option<(u128, Record, usize)>> = Vec::with_capacity(readers.len());
+        let mut heap: BinaryHeap<Reverse<(u128, usize)>> = BinaryHeap::new();
+        for (i, (_is_source, reader)) in readers.iter_mut().enumerate() {
+            let head = reader.next_row()?;
+            if let Some((hash, _record, _copies)) = &head {
+                heap.push(Reverse((*hash, i)));
+            }
+            heads.push(head);
+        }
+        while let Some(Reverse((hash, _i))) = heap.peek().copied() {
+            let mut copies: i64 = 0;
+            let mut source_record: Option<Record> = None;
+            let mut dest_record: Option<Record> = None;
+            while let Some(Reverse((next_hash, i))) = heap.peek().copied() {
+                if next_hash != hash {
+                    break;
+                }
+                heap.pop();
+                let (is_source, reader) = &mut readers[i];
+                if let Some((_hash, record, count)) = heads[i].take() {
+                    if *is_source {
+                        copies += count as i64;
+                        source_record.get_or_insert(record);
+                    } else {
+                        copies -= count as i64;
+                        dest_record.get_or_insert(record);
+                    }
+                }
+                heads[i] = reader.next_row()?;
+                if let Some((hash, _record, _copies)) = &heads[i] {
+                    heap.push(Reverse((*hash, i)));
+                }
+            }
+            if copies > 0 {
+                if let Some(record) = source_record {
+                    emit(true, hash, record, copies as usize)?;
+                }
+            } else if copies < 0 {
+                if let Some(record) = dest_record {
+                    emit(false, hash, record, (-copies) as usize)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A row of a run sorted by key: its normalized key, fingerprint, record and number of copies
+type KeyedRow = (Vec<Value>, u128, Record, usize);
+
+/// Sorts the unmatched rows of one side by key (see pg_hasher::normalized_key): rows are collected in memory,
+/// and written to a sorted run file when there are too many
+struct KeySorter<'a> {
+    dir: PathBuf,
+    side: &'static str,
+    key_columns: &'a [String],
+    options: &'a HashOptions,
+    max_rows: usize,
+    max_bytes: usize,
+    rows: Vec<KeyedRow>,
+    bytes: usize,
+    runs: Vec<RunFile>,
+}
+
+impl<'a> KeySorter<'a> {
+    fn new(store: &SpillStore, side: &'static str, key_columns: &'a [String], options: &'a HashOptions) -> KeySorter<'a> {
+        KeySorter {
+            dir: store.dir.clone(), side, key_columns, options, max_rows: store.max_rows, max_bytes: store.max_bytes,
+            rows: Vec::new(), bytes: 0, runs: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, hash: u128, record: Record, copies: usize) -> Result<()> {
+        let key = pg_hasher::normalized_key(self.key_columns, &record, self.options)?;
+        self.bytes += differ::record_bytes(&record);
+        self.rows.push((key, hash, record, copies));
+        if self.rows.len() > self.max_rows || (self.max_bytes > 0 && self.bytes > self.max_bytes) {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self) -> Result<()> {
+        self.rows.sort_by(|(a, ..), (b, ..)| pg_hasher::normalized_key_cmp(a, b));
+        let rows = std::mem::take(&mut self.rows);
+        self.bytes = 0;
+        if let Some((_key, _hash, record, _copies)) = rows.first() {
+            let mut writer = RunWriter::create(&self.dir, self.side, record.columns.clone())?;
+            for (_key, hash, record, copies) in &rows {
+                writer.write(*hash, record, *copies)?;
+            }
+            self.runs.push(writer.finish()?);
+        }
+        if self.runs.len() > MAX_RUNS {
+            // Merge all runs into one
+            let columns = self.runs[0].columns.clone();
+            let mut writer = RunWriter::create(&self.dir, self.side, columns)?;
+            let mut merge = KeyMerge::new(std::mem::take(&mut self.runs), Vec::new(), self.key_columns, self.options)?;
+            while let Some((_key, hash, record, copies)) = merge.next_row()? {
+                writer.write(hash, &record, copies)?;
+            }
+            self.runs.push(writer.finish()?);
+        }
+        Ok(())
+    }
+
+    /// All rows in order of key, with every copy of a duplicate row as a separate row
+    fn into_rows(mut self) -> Result<KeyMerge<'a>> {
+        self.rows.sort_by(|(a, ..), (b, ..)| pg_hasher::normalized_key_cmp(a, b));
+        KeyMerge::new(self.runs, self.rows, self.key_columns, self.options)
+    }
+}
+
+/// Merges runs that are sorted by key, and sorted rows that are still in memory, into one sequence sorted by key.
+/// Rows with the same key come in the order of their runs. The run files are removed when it is dropped.
+struct KeyMerge<'a> {
+    key_columns: &'a [String],
+    options: &'a HashOptions,
+    readers: Vec<RunReader>,
+    rows: std::vec::IntoIter<KeyedRow>,
+    /// The next row of every reader, and of the rows in memory last
+    heads: Vec<Option<KeyedRow>>,
+    /// The copies of a row that are still to be handed out, as an iterator
+    pending: Option<(Vec<Value>, Record, usize)>,
+    _runs: Vec<RunFile>,
+}
+
+impl<'a> KeyMerge<'a> {
+    fn new(runs: Vec<RunFile>, rows: Vec<KeyedRow>, key_columns: &'a [String], options: &'a HashOptions) -> Result<KeyMerge<'a>> {
+        let readers = runs.iter().map(RunFile::reader).collect::<Result<Vec<RunReader>>>()?;
+        let mut merge = KeyMerge {
+            key_columns, options, readers, rows: rows.into_iter(), heads: Vec::new(), pending: None, _runs: runs,
+        };
+        for i in 0..=merge.readers.len() {
+            let head = merge.read(i)?;
+            merge.heads.push(head);
+        }
+        Ok(merge)
+    }
+
+    /// The next row of reader i, or of the rows in memory
+    fn read(&mut self, i: usize) -> Result<Option<KeyedRow>> {
+        if i == self.readers.len() {
+            return Ok(self.rows.next());
+        }
+        match self.readers[i].next_row()? {
+            Some((hash, record, copies)) => {
+                let key = pg_hasher::normalized_key(self.key_columns, &record, self.options)?;
+                Ok(Some((key, hash, record, copies)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_row(&mut self) -> Result<Option<KeyedRow>> {
+        let mut first: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, ..)) = head {
+                let before = match first.and_then(|f| self.heads[f].as_ref()) {
+                    Some((first_key, ..)) => pg_hasher::normalized_key_cmp(key, first_key) == cmp::Ordering::Less,
+                    None => true,
+                };
+                if before {
+                    first = Some(i);
+                }
+            }
+        }
+        match first {
+            Some(i) => {
+                let next = self.read(i)?;
+                Ok(std::mem::replace(&mut self.heads[i], next))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'a> Iterator for KeyMerge<'a> {
+    type Item = Result<KeyedRecord>;
+
+    fn next(&mut self) -> Option<Result<KeyedRecord>> {
+        if let Some((key, record, copies)) = self.pending.take() {
+            if copies > 1 {
+                self.pending = Some((key.clone(), record.clone(), copies - 1));
+            }
+            return Some(Ok((key, record)));
+        }
+        match self.next_row() {
+            Ok(Some((key, _hash, record, copies))) => {
+                self.pending = Some((key, record, copies));
+                self.next()
+            },
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use tokio_postgres::types::Type;
+    use super::*;
+    use crate::pg_hasher::test_support::{self, options, text};
+
+    static TEST_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    /// An empty directory of its own for every test
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "dbdiff-spill-test-{}-{}", std::process::id(), TEST_DIRS.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files_in(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    /// Rows of a table with an id and a name
+    fn record(id: i64, name: &str) -> Record {
+        test_support::record(&[("id", Type::INT8), ("name", Type::TEXT)], vec![Value::Int(id), text(name)])
+    }
+
+    /// Unmatched rows with their fingerprints
+    fn unmatched(rows: &[(i64, &str, usize)]) -> UnmatchedRows {
+        let mut unmatched = UnmatchedRows::new();
+        for (id, name, copies) in rows {
+            let record = record(*id, name);
+            unmatched.add_record(pg_hasher::row_hasher(&record, &options()), record, *copies);
+        }
+        unmatched
+    }
+
+    /// The ids of the unmatched rows with their number of copies, in order of id
+    fn ids(rows: UnmatchedRows) -> Vec<(i64, usize)> {
+        let mut ids: Vec<(i64, usize)> = rows.into_counted_rows().into_iter()
+            .map(|(record, copies)| match record.values[0] { Value::Int(id) => (id, copies), _ => panic!("no id") })
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn copies_cancel_out() {
+        let dir = test_dir();
+        let mut store = SpillStore::new(dir.clone(), 10, 0);
+        store.spill(&mut unmatched(&[(1, "a", 3), (2, "b", 1)]), &mut unmatched(&[(3, "c", 1)])).unwrap();
+        store.spill(&mut unmatched(&[(3, "c", 1)]), &mut unmatched(&[(1, "a", 1), (4, "d", 2)])).unwrap();
+        assert_eq!(files_in(&dir), 4);
+        let (source, dest) = store.finish(unmatched(&[]), unmatched(&[(2, "b", 1), (3, "c", 2)])).unwrap();
+        assert_eq!(ids(source), vec![(1, 2)]);
+        assert_eq!(ids(dest), vec![(3, 2), (4, 2)]);
+        assert_eq!(files_in(&dir), 0);
+        std::fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn compact() {
+        let dir = test_dir();
+        let mut store = SpillStore::new(dir.clone(), 10, 0);
+        for id in 0..=MAX_RUNS as i64 {
+            store.spill(&mut unmatched(&[(id, "a", 1)]), &mut unmatched(&[(id + 1, "a", 1)])).unwrap();
+        }
+        assert_eq!((store.source.len(), store.dest.len()), (1, 1));
+        assert_eq!(files_in(&dir), 2);
+        let mut rows: Vec<(bool, i64)> = Vec::new();
+        store.finish_unkeyed(unmatched(&[]), unmatched(&[]), |is_source, record, _copies| {
+            rows.push((is_source, match record.values[0] { Value::Int(id) => id, _ => panic!("no id") }));
+            Ok(())
+        }).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.contains(&(true, 0)) && rows.contains(&(false, MAX_RUNS as i64 + 1)));
+        assert_eq!(files_in(&dir), 0);
+        std::fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn keyed() {
+        let dir = test_dir();
+        // Two rows per sorted run, so that the rows are merged from several runs
+        let mut store = SpillStore::new(dir.clone(), 2, 0);
+        let source: Vec<(i64, &str, usize)> = (1..=20).rev().map(|id| (id, "a", 1)).collect();
+        store.spill(&mut unmatched(&source), &mut unmatched(&[(5, "b", 1), (30, "a", 2)])).unwrap();
+        let mut diffs: Vec<String> = Vec::new();
+        store.finish_keyed(unmatched(&[(30, "a", 1)]), unmatched(&[(7, "a", 1)]), &[String::from("id")], &options(), |diff| {
+            diffs.push(match diff {
+                RowDiff::Added { row } => format!("> {}", row.values[0]),
+                RowDiff::Removed { row } => format!("< {}", row.values[0]),
+                RowDiff::Changed { key, .. } => format!("~ {}", key[0]),
+            });
+            Ok(())
+        }).unwrap();
+        let mut expected: Vec<String> = (1..=20).filter(|id| *id != 7).map(|id| format!("< {}", id)).collect();
+        expected[4] = String::from("~ 5");
+        expected.push(String::from("> 30"));
+        assert_eq!(diffs, expected);
+        assert_eq!(files_in(&dir), 0);
+        std::fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use futures::{pin_mut, stream, StreamExt};
+use tokio_postgres::Client;
+use crate::checksum;
+use crate::chunks;
+use crate::cli;
+use crate::compare;
+use crate::connection;
+use crate::differ;
+use crate::exit::ErrorKind;
+use crate::pg_hasher;
+use crate::query;
+
+/// The outcome of the comparison of one table in a multi-table run
+pub enum TableOutcome {
+    OnlyOnSource,
+    OnlyOnDest,
+    Compared { warnings: Vec<String>, comparison: compare::Comparison },
+    Failed(anyhow::Error),
+}
+
+pub struct TableResult {
+    pub table: String,
+    /// The settings this table was compared with (table names and key columns)
+    pub args: cli::Params,
+    pub outcome: TableOutcome,
+}
+
+/// Match a name against a pattern with * (any number of characters) and ? (one character)
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) => p == n && glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// List the tables in a schema, optionally only those matching one of the patterns
+async fn list_tables(client: &Client, schema: &str, patterns: &[String]) -> Result<Vec<String>> {
+    let rows = client.query(
+        "select c.relname::text from pg_catalog.pg_class c \
+         join pg_catalog.pg_namespace n on n.oid = c.relnamespace \
+         where n.nspname = $1 and c.relkind in ('r', 'p') and not c.relispartition \
+         order by 1",
+        &[&schema],
+    ).await.context(ErrorKind::Query)?;
+    let mut tables: Vec<String> = Vec::new();
+    for row in rows {
+        let table: String = row.get(0);
+        let name: Vec<char> = table.chars().collect();
+        if patterns.is_empty() || patterns.iter().any(|p| glob_match(&p.chars().collect::<Vec<char>>(), &name)) {
+            tables.push(table);
+        }
+    }
+    Ok(tables)
+}
+
+/// The primary key columns of a table, in key order
+pub async fn primary_key(client: &Client, table_name: &str) -> Result<Vec<String>> {
+    let rows = client.query(
+        "select a.attname::text from pg_catalog.pg_index i \
+         join pg_catalog.pg_attribute a on a.attrelid = i.indrelid and a.attnum = any(i.indkey) \
+         where i.indrelid = to_regclass($1) and i.indisprimary \
+         order by array_position(i.indkey::int2[], a.attnum)",
+        &[&pg_hasher::table_as_name(table_name)],
+    ).await.context(ErrorKind::Query)?;
+    Ok(rows.iter().map(|r| r.get::<usize, String>(0)).collect())
+}
+
+/// Compare one table on its own pair of connections.
+/// Without --key, the primary key of the source table is used.
+async fn compare_table(args: &mut cli::Params, hash_options: &pg_hasher::HashOptions,
+                       unsupported_types: query::UnsupportedTypes) -> Result<(Vec<String>, compare::Comparison)> {
+    let (source, dest) = connection::connect_pair(args).await?;
+    if args.key_columns.is_empty() {
+        args.key_columns = primary_key(&source, &args.source_table_name).await?;
+    }
+    if args.key_columns.is_empty() && (args.sorted || args.output_format == "sync") {
+        return Err(anyhow::anyhow!("Table has no primary key, and --sorted and the sync format require key columns"));
+    }
+    if args.checksum {
+        return checksum::compare_checksummed(&source, &dest, args, hash_options, unsupported_types).await;
+    }
+    if args.chunks > 1 {
+        return chunks::compare_chunked(&source, &dest, args, hash_options, unsupported_types).await;
+    }
+    let (source_rows, dest_rows, warnings) = compare::query_streams(&source, &dest, args, hash_options, unsupported_types).await?;
+    pin_mut!(source_rows);
+    pin_mut!(dest_rows);
+    let comparison = if args.sorted {
+        // Other tables are compared at the same time, so differences are collected instead of streamed
+        let mut diffs: Vec<differ::RowDiff> = Vec::new();
+        let (source_read, dest_read) = differ::merge_diff(
+            &args.key_columns, hash_options, source_rows.as_mut(), dest_rows.as_mut(),
+            |diff| {
+                diffs.push(diff);
+                Ok(())
+            },
+        ).await?;
+        compare::Comparison {
+            source_rows: source_read,
+            dest_rows: dest_read,
+            truncated: false,
+            differences: compare::Differences::Keyed(diffs),
+        }
+    } else {
+        compare::hash_diff(source_rows.as_mut(), dest_rows.as_mut(), args, hash_options).await?
+    };
+    Ok((warnings, comparison))
+}
+
+/// Compare all (matching) tables of a schema, with at most args.parallel tables at the same time.
+/// Results are sorted by table name.
+pub async fn compare_tables(args: &cli::Params, unsupported_types: query::UnsupportedTypes) -> Result<Vec<TableResult>> {
+    let (source, dest) = connection::connect_pair(args).await?;
+    let source_tables = list_tables(&source, &args.schema, &args.tables).await?;
+    let dest_tables = list_tables(&dest, &args.dest_schema, &args.tables).await?;
+
+    let mut results: Vec<TableResult> = Vec::new();
+    let mut jobs: Vec<(String, cli::Params)> = Vec::new();
+    for table in &source_tables {
+        let mut table_args = args.clone();
+        table_args.source_table_name = format!("{}.{}", args.schema, table);
+        table_args.dest_table_name = format!("{}.{}", args.dest_schema, table);
+        table_args.source_query = String::new();
+        table_args.dest_query = String::new();
+        if dest_tables.contains(table) {
+            jobs.push((table.clone(), table_args));
+        } else {
+            results.push(TableResult { table: table.clone(), args: table_args, outcome: TableOutcome::OnlyOnSource });
+        }
+    }
+    for table in dest_tables.iter().filter(|t| !source_tables.contains(t)) {
+        let mut table_args = args.clone();
+        table_args.dest_table_name = format!("{}.{}", args.dest_schema, table);
+        results.push(TableResult { table: table.clone(), args: table_args, outcome: TableOutcome::OnlyOnDest });
+    }
+
+    results.extend(compare_pairs(args, jobs, unsupported_types).await?);
+    results.sort_by(|a, b| a.table.cmp(&b.table));
+    Ok(results)
+}
+
+/// Compare pairs of tables or queries, each with its own settings, with at most args.parallel pairs
+/// at the same time. Results are in the order of the pairs.
+pub async fn compare_pairs(args: &cli::Params, pairs: Vec<(String, cli::Params)>,
+                           unsupported_types: query::UnsupportedTypes) -> Result<Vec<TableResult>> {
+    // Tasks are only spawned when buffered polls for them, which limits the parallelism
+    let compared: Vec<Result<TableResult, tokio::task::JoinError>> = stream::iter(pairs)
+        .map(|(table, mut table_args)| {
+            tokio::spawn(async move {
+                let outcome = match table_args.hash_options() {
+                    Ok(hash_options) => match compare_table(&mut table_args, &hash_options, unsupported_types).await {
+                        Ok((warnings, comparison)) => TableOutcome::Compared { warnings, comparison },
+                        Err(e) => TableOutcome::Failed(e),
+                    },
+                    Err(e) => TableOutcome::Failed(e),
+                };
+                TableResult { table, args: table_args, outcome }
+            })
+        })
+        .buffered(args.parallel.max(1))
+        .collect()
+        .await;
+    let mut results: Vec<TableResult> = Vec::new();
+    for result in compared {
+        results.push(result?);
+    }
+    Ok(results)
+}